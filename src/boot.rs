@@ -1,8 +1,13 @@
 //! Data for `boot.cfg`
 
+use assembly_pack::pki::core::PackIndexFile;
+use assembly_xml::universe_config::{Environment, Server};
 use regex::Regex;
-use serde::Serialize;
-use std::{borrow::Cow, fmt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, fmt, path::Path, str::FromStr};
+
+use crate::config::{PatcherConfig, Variant};
 
 struct LDFWriter<T> {
     inner: T,
@@ -62,6 +67,102 @@ impl<T: std::fmt::Write> LDFWriter<T> {
 }
 
 impl BootConfig {
+    /// The config for a client of `variant` that connects to `server`
+    ///
+    /// The client only looks into the packs of `pki` when `USE_CATALOG` is
+    /// set, so that is derived from the catalog. `overrides` are applied last,
+    /// like [`BootConfig::set`].
+    pub fn new(
+        server: &Server,
+        env_info: &Environment,
+        config: &PatcherConfig,
+        variant: Variant,
+        pki: &PackIndexFile,
+        overrides: &[(String, String)],
+    ) -> Result<Self, InvalidOverride> {
+        let patch_server_port = if server.cdn_info.secure { 443 } else { 80 };
+        let mut boot = Self {
+            server_name: server.name.clone(),
+            patch_server_ip: server.cdn_info.patcher_url.clone(),
+            patch_server_port,
+            auth_server_ip: server.authentication_ip.clone(),
+            logging: server.log_level,
+            data_center_id: server.data_center_id,
+            cp_code: server.cdn_info.cp_code,
+            akamai_dlm: server.cdn_info.use_dlm,
+            patch_server_dir: server.cdn_info.patcher_dir.clone(),
+            ugc_use_3d_services: server.use3d_services,
+            ugc_server_ip: server.ugc_cdn_info.patcher_url.clone(),
+            ugc_server_dir: server.ugc_cdn_info.patcher_dir.clone(),
+            manifest_file: config.manifest_file(variant).to_owned(),
+            passurl: env_info.account_info.send_password_url.clone(),
+            sign_in_url: env_info.account_info.sign_in_url.clone(),
+            sign_up_url: env_info.account_info.sign_up_url.clone(),
+            register_url: env_info.game_info.client_url.clone(),
+            crash_log_url: env_info.game_info.crash_log_url.clone(),
+            locale: server.language.clone(),
+            track_disk_usage: true,
+            use_catalog: !pki.archives.is_empty(),
+        };
+        for (key, value) in overrides {
+            boot.set(key, value)?;
+        }
+        Ok(boot)
+    }
+
+    /// Load a config from a JSON or TOML template
+    pub fn parse(text: &str, format: Format) -> color_eyre::Result<Self> {
+        match format {
+            Format::Cfg => Err(color_eyre::eyre::eyre!(
+                "Templates must be JSON or TOML, not boot.cfg"
+            )),
+            Format::Json => Ok(serde_json::from_str(text)?),
+            Format::Toml => Ok(toml::from_str(text)?),
+        }
+    }
+
+    /// Print the config in `format`
+    pub fn render(&self, format: Format) -> color_eyre::Result<String> {
+        match format {
+            Format::Cfg => Ok(self.to_cfg()?),
+            Format::Json => Ok(serde_json::to_string_pretty(self)? + "\n"),
+            Format::Toml => Ok(toml::to_string(self)?),
+        }
+    }
+
+    /// Replace the value of `key` (e.g. `AUTHSERVERIP`)
+    ///
+    /// The value is parsed according to the type of the key, flags accept
+    /// `0`/`1` like in `boot.cfg` as well as `false`/`true`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), InvalidOverride> {
+        let invalid = |message: String| InvalidOverride {
+            key: key.to_owned(),
+            message,
+        };
+        let mut fields = match serde_json::to_value(&*self) {
+            Ok(Value::Object(fields)) => fields,
+            _ => unreachable!("BootConfig serializes to an object"),
+        };
+        let field = fields
+            .get_mut(&key.to_ascii_uppercase())
+            .ok_or_else(|| invalid(String::from("unknown key")))?;
+        *field = match field {
+            Value::Bool(_) => match value {
+                "1" | "true" => Value::Bool(true),
+                "0" | "false" => Value::Bool(false),
+                _ => return Err(invalid(format!("expected 0 or 1, got {:?}", value))),
+            },
+            Value::Number(_) => value
+                .parse::<i64>()
+                .map(Value::from)
+                .map_err(|e| invalid(format!("{:?}: {}", value, e)))?,
+            _ => Value::from(value),
+        };
+        *self =
+            serde_json::from_value(Value::Object(fields)).map_err(|e| invalid(e.to_string()))?;
+        Ok(())
+    }
+
     pub fn to_cfg(&self) -> Result<String, fmt::Error> {
         let mut writer = LDFWriter::new(String::new());
         writer.set_delim(",\r\n".to_string());
@@ -90,7 +191,7 @@ impl BootConfig {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct BootConfig {
     #[serde(rename = "SERVERNAME")]
     pub server_name: String,
@@ -136,36 +237,269 @@ pub struct BootConfig {
     pub use_catalog: bool,
 }
 
-pub struct Token<'a> {
-    pub install_path: std::borrow::Cow<'a, str>,
+/// The file formats for a [`BootConfig`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The LDF format that the client reads
+    Cfg,
+    Json,
+    Toml,
 }
 
-impl<'a> Token<'a> {
-    pub fn resolve(self, input: &str) -> Cow<str> {
-        let pattern = Regex::new(r"\{%([a-z]+)\}").unwrap();
-        pattern.replace(input, self)
+impl Format {
+    /// Guess the format from the extension of `path`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?;
+        ext.parse().ok()
     }
 }
 
-impl<'a> regex::Replacer for Token<'a> {
-    fn replace_append(&mut self, caps: &regex::Captures<'_>, dst: &mut String) {
-        let m = caps.get(1).unwrap();
-        if m.as_str() == "installpath" {
-            dst.push_str(&self.install_path)
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cfg" => Ok(Self::Cfg),
+            "json" => Ok(Self::Json),
+            "toml" => Ok(Self::Toml),
+            _ => Err(format!("expected cfg, json or toml, got {:?}", s)),
+        }
+    }
+}
+
+/// An override for a key that does not exist or with a value of the wrong type
+#[derive(Debug)]
+pub struct InvalidOverride {
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for InvalidOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid override for {}: {}", self.key, self.message)
+    }
+}
+
+impl std::error::Error for InvalidOverride {}
+
+/// A placeholder without a value
+#[derive(Debug, Clone)]
+pub struct UnknownToken(pub String);
+
+impl fmt::Display for UnknownToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown token '{{%{}}}'", self.0)
+    }
+}
+
+impl std::error::Error for UnknownToken {}
+
+/// Values for the `{%name}` placeholders in `patcher.ini`
+///
+/// The patcher defines `installpath`, `downloaddir`, `patcherdir` and `locale`,
+/// users can add more with `--var name=value` or `LUX_PATCHER_VAR_<NAME>`.
+#[derive(Debug, Default, Clone)]
+pub struct Tokens {
+    values: BTreeMap<String, String>,
+}
+
+/// The prefix for environment variables that define tokens
+const ENV_PREFIX: &str = "LUX_PATCHER_VAR_";
+
+impl Tokens {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the value for a token, names are case insensitive
+    pub fn set(&mut self, name: &str, value: impl Into<String>) {
+        self.values.insert(name.to_ascii_lowercase(), value.into());
+    }
+
+    /// Add the tokens defined in the environment
+    pub fn set_from_env(&mut self) {
+        for (key, value) in std::env::vars() {
+            if let Some(name) = key.strip_prefix(ENV_PREFIX) {
+                self.set(name, value);
+            }
+        }
+    }
+
+    /// Replace all tokens in `input`
+    pub fn resolve(&self, input: &str) -> Result<String, UnknownToken> {
+        let pattern = Regex::new(r"\{%([A-Za-z0-9_]+)\}").unwrap();
+        let mut output = String::with_capacity(input.len());
+        let mut last = 0;
+        for caps in pattern.captures_iter(input) {
+            let m = caps.get(0).unwrap();
+            let name = caps[1].to_ascii_lowercase();
+            let value = self.values.get(&name).ok_or(UnknownToken(name))?;
+            output.push_str(&input[last..m.start()]);
+            output.push_str(value);
+            last = m.end();
         }
+        output.push_str(&input[last..]);
+        Ok(output)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::borrow::Cow;
+    use super::{BootConfig, Format, Tokens};
+    use crate::{
+        config::{PatcherConfig, Variant},
+        universe::{self, tests::ENVIRONMENT_INFO},
+    };
+    use assembly_pack::pki::core::{PackFileRef, PackIndexFile};
+
+    const PATCHER_INI: &str =
+        "[patcher]\r\ndefaultmanifestfile=trunk.txt\r\nminimalmanifestfile=frontend.txt\r\n";
+
+    const MINIMAL_CFG: &str = "\
+SERVERNAME=0:Overbuild Universe (US),\r
+PATCHSERVERIP=0:localhost:8000,\r
+PATCHSERVERPORT=1:80,\r
+AUTHSERVERIP=0:127.0.0.1,\r
+LOGGING=1:1,\r
+DATACENTERID=5:150,\r
+CPCODE=1:89164,\r
+AKAMAIDLM=7:0,\r
+PATCHSERVERDIR=0:luclient,\r
+UGCUSE3DSERVICES=7:0,\r
+UGCSERVERIP=0:localhost,\r
+UGCSERVERDIR=0:ugc,\r
+MANIFESTFILE=0:frontend.txt,\r
+PASSURL=0:http://a/pw,\r
+SIGNINURL=0:http://a/in,\r
+SIGNUPURL=0:http://a/up,\r
+REGISTERURL=0:http://a/reg,\r
+CRASHLOGURL=0:http://a/crash,\r
+LOCALE=0:en_US,\r
+TRACK_DSK_USAGE=7:1,\r
+USE_CATALOG=7:0";
+
+    const DEFAULT_CFG: &str = "\
+SERVERNAME=0:Overbuild Universe (US),\r
+PATCHSERVERIP=0:localhost:8000,\r
+PATCHSERVERPORT=1:80,\r
+AUTHSERVERIP=0:127.0.0.1,\r
+LOGGING=1:1,\r
+DATACENTERID=5:150,\r
+CPCODE=1:89164,\r
+AKAMAIDLM=7:0,\r
+PATCHSERVERDIR=0:luclient,\r
+UGCUSE3DSERVICES=7:0,\r
+UGCSERVERIP=0:localhost,\r
+UGCSERVERDIR=0:ugc,\r
+MANIFESTFILE=0:trunk.txt,\r
+PASSURL=0:http://a/pw,\r
+SIGNINURL=0:http://a/in,\r
+SIGNUPURL=0:http://a/up,\r
+REGISTERURL=0:http://a/reg,\r
+CRASHLOGURL=0:http://a/crash,\r
+LOCALE=0:en_US,\r
+TRACK_DSK_USAGE=7:1,\r
+USE_CATALOG=7:1";
 
     #[test]
-    fn test_token() {
-        let token = super::Token {
-            install_path: Cow::Borrowed("some\\path"),
+    fn test_variants() {
+        let env_info = universe::parse(ENVIRONMENT_INFO).unwrap();
+        let server = &env_info.servers.servers[0];
+        let config: PatcherConfig = PATCHER_INI.parse().unwrap();
+        let mut pki = PackIndexFile {
+            archives: vec![],
+            files: Default::default(),
         };
-        let res = token.resolve("{%installpath}\\client\\boot.cfg");
-        assert_eq!(res.as_ref(), "some\\path\\client\\boot.cfg")
+
+        let minimal = BootConfig::new(server, &env_info, &config, Variant::Minimal, &pki, &[]);
+        assert_eq!(minimal.unwrap().to_cfg().unwrap(), MINIMAL_CFG);
+
+        pki.archives.push(PackFileRef {
+            path: String::from("client\\res\\pack\\front.pk"),
+        });
+        let default = BootConfig::new(server, &env_info, &config, Variant::Default, &pki, &[]);
+        assert_eq!(default.unwrap().to_cfg().unwrap(), DEFAULT_CFG);
+
+        let overrides = [(String::from("TRACK_DSK_USAGE"), String::from("0"))];
+        let untracked = BootConfig::new(
+            server,
+            &env_info,
+            &config,
+            Variant::Default,
+            &pki,
+            &overrides,
+        );
+        let expected = DEFAULT_CFG.replace("TRACK_DSK_USAGE=7:1", "TRACK_DSK_USAGE=7:0");
+        assert_eq!(untracked.unwrap().to_cfg().unwrap(), expected);
+    }
+
+    const TEMPLATE: &str = r#"{
+        "SERVERNAME": "Local",
+        "PATCHSERVERIP": "localhost",
+        "PATCHSERVERPORT": 80,
+        "AUTHSERVERIP": "localhost",
+        "LOGGING": 100,
+        "DATACENTERID": 150,
+        "CPCODE": 89164,
+        "AKAMAIDLM": false,
+        "PATCHSERVERDIR": "luclient",
+        "UGCUSE3DSERVICES": true,
+        "UGCSERVERIP": "localhost",
+        "UGCSERVERDIR": "3dservices",
+        "MANIFESTFILE": "trunk.txt",
+        "PASSURL": "",
+        "SIGNINURL": "",
+        "SIGNUPURL": "",
+        "REGISTERURL": "",
+        "CRASHLOGURL": "",
+        "LOCALE": "en_US",
+        "TRACK_DSK_USAGE": true,
+        "USE_CATALOG": false
+    }"#;
+
+    #[test]
+    fn test_formats() {
+        let mut config = BootConfig::parse(TEMPLATE, Format::Json).unwrap();
+        config.set("authserverip", "10.0.0.2").unwrap();
+        config.set("DATACENTERID", "1").unwrap();
+        config.set("USE_CATALOG", "1").unwrap();
+        assert_eq!(config.auth_server_ip, "10.0.0.2");
+        assert_eq!(config.data_center_id, 1);
+        assert!(config.use_catalog);
+
+        assert!(config.set("AUTHSERVER", "x").is_err());
+        assert!(config.set("DATACENTERID", "-1").is_err());
+        assert!(config.set("AKAMAIDLM", "yes").is_err());
+
+        for format in [Format::Json, Format::Toml] {
+            let text = config.render(format).unwrap();
+            let copy = BootConfig::parse(&text, format).unwrap();
+            assert_eq!(copy.to_cfg(), config.to_cfg());
+        }
+        let cfg = config.render(Format::Cfg).unwrap();
+        assert!(cfg.starts_with("SERVERNAME=0:Local,\r\nPATCHSERVERIP=0:localhost,"));
+        assert!(cfg.contains("AUTHSERVERIP=0:10.0.0.2,\r\n"));
+    }
+
+    #[test]
+    fn test_token() {
+        let mut tokens = Tokens::new();
+        tokens.set("installpath", "some\\path");
+        let res = tokens.resolve("{%installpath}\\client\\boot.cfg").unwrap();
+        assert_eq!(res, "some\\path\\client\\boot.cfg")
+    }
+
+    #[test]
+    fn test_tokens() {
+        let mut tokens = Tokens::new();
+        tokens.set("installpath", "C:\\LU");
+        tokens.set("Locale", "en_US");
+        let res = tokens
+            .resolve("{%installpath}\\{%locale}\\{%INSTALLPATH}")
+            .unwrap();
+        assert_eq!(res, "C:\\LU\\en_US\\C:\\LU");
+
+        let err = tokens.resolve("{%installpath}\\{%other}").unwrap_err();
+        assert_eq!(err.0, "other");
     }
 }
@@ -1,10 +1,21 @@
+use crate::util::with_suffix;
 use assembly_pack::md5::MD5Sum;
+use log::warn;
+use nom::{
+    bytes::complete::{take_while1, take_while_m_n},
+    character::complete::{char, digit1},
+    combinator::{all_consuming, map_res, opt},
+    number::complete::double,
+    sequence::{terminated, tuple},
+    Finish, IResult,
+};
+
 use std::{
     collections::BTreeMap,
     fmt::Display,
-    fs::File,
+    fs::{File, OpenOptions},
     io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
-    path::Path,
+    path::{Path, PathBuf},
     //    time::{SystemTime, SystemTimeError},
 };
 
@@ -15,6 +26,7 @@ use std::{
 }*/
 
 /// One entry in the cache
+#[derive(Debug, PartialEq)]
 pub struct CacheEntry {
     /// The time the file was written
     pub mtime: Option<f64>,
@@ -33,6 +45,7 @@ impl Display for CacheEntry {
     }
 }
 
+#[derive(Debug, PartialEq)]
 pub struct CacheKey(String);
 
 impl CacheKey {
@@ -43,6 +56,13 @@ impl CacheKey {
 
 pub struct Cache {
     entries: BTreeMap<String, CacheEntry>,
+    /// Append-only log of entries recorded since the last save
+    journal: Option<File>,
+}
+
+/// The journal that belongs to the cache file at `path`
+pub fn journal_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".journal")
 }
 
 impl Cache {
@@ -50,10 +70,34 @@ impl Cache {
     pub fn new() -> Self {
         Self {
             entries: BTreeMap::new(),
+            journal: None,
         }
     }
 
+    /// Open the cache file at `path` for incremental updates
+    ///
+    /// This replays the journal of an interrupted run, compacts it into the
+    /// cache file and starts a new journal that [`Cache::record`] appends to.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let mut cache = Self::new();
+        cache.load(path)?;
+
+        let journal_path = journal_path(path);
+        cache.load(&journal_path)?;
+        cache.save(path)?;
+
+        let journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)?;
+        cache.journal = Some(journal);
+        Ok(cache)
+    }
+
     /// Load from a cache file
+    ///
+    /// Lines that can't be parsed are skipped with a warning, so that a damaged
+    /// file only invalidates the affected entries.
     pub fn load(&mut self, path: &Path) -> std::io::Result<()> {
         let f = match File::open(path) {
             Ok(f) => f,
@@ -61,23 +105,53 @@ impl Cache {
             Err(e) => return Err(e),
         };
         let reader = BufReader::new(f);
-        for line in reader.lines() {
-            let line = line?;
-            let mut parts = line.split(',');
-            let key = CacheKey(parts.next().unwrap().to_owned());
-            let mtime = parts.next().unwrap().parse().ok();
-            let size = parts.next().unwrap().parse().unwrap();
-            let hash = parts.next().unwrap().parse().unwrap();
-            self.insert(key, CacheEntry { mtime, size, hash })
+        for (line_no, column, line) in self.load_from(reader)? {
+            warn!(
+                "{}:{}:{}: skipping malformed entry {:?}",
+                path.display(),
+                line_no,
+                column,
+                line
+            );
         }
         Ok(())
     }
 
+    /// Load entries from a reader, returning line, column and content of skipped lines
+    fn load_from<R: BufRead>(&mut self, reader: R) -> std::io::Result<Vec<(usize, usize, String)>> {
+        let mut skipped = Vec::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            match parse_line(line) {
+                Ok((key, entry)) => self.insert(key, entry),
+                Err(column) => skipped.push((index + 1, column, line.to_owned())),
+            }
+        }
+        Ok(skipped)
+    }
+
+    /// Atomically replace the cache file with the current entries
+    ///
+    /// This also truncates the journal, as all of its entries are now in the file.
     pub fn save(&self, path: &Path) -> std::io::Result<()> {
-        let f = File::create(path)?;
+        let tmp_path = with_suffix(path, ".tmp");
+        let f = File::create(&tmp_path)?;
         let mut writer = BufWriter::new(f);
         write!(writer, "{}", self)?;
-        Ok(())
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+
+        match &self.journal {
+            Some(journal) => journal.set_len(0),
+            None => match std::fs::remove_file(journal_path(path)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+        }
     }
 
     /// Check whether this key is present
@@ -85,10 +159,55 @@ impl Cache {
         self.entries.get(&key.0)
     }
 
+    /// The hashes of all files in the cache
+    pub fn hashes(&self) -> impl Iterator<Item = MD5Sum> + '_ {
+        self.entries.values().map(|e| e.hash)
+    }
+
     /// Insert a new entry
     pub fn insert(&mut self, key: CacheKey, value: CacheEntry) {
         self.entries.insert(key.0, value);
     }
+
+    /// Insert a new entry and append it to the journal, if there is one
+    pub fn record(&mut self, key: CacheKey, value: CacheEntry) -> std::io::Result<()> {
+        if let Some(journal) = &mut self.journal {
+            let line = format!("{},{}\n", key.0, value);
+            journal.write_all(line.as_bytes())?;
+            journal.sync_data()?;
+        }
+        self.insert(key, value);
+        Ok(())
+    }
+}
+
+fn md5(input: &str) -> IResult<&str, MD5Sum> {
+    map_res(
+        take_while_m_n(32, 32, |c: char| c.is_ascii_hexdigit()),
+        str::parse,
+    )(input)
+}
+
+fn entry_line(input: &str) -> IResult<&str, (CacheKey, CacheEntry)> {
+    let (input, (key, mtime, size, hash)) = tuple((
+        terminated(take_while1(|c: char| c != ','), char(',')),
+        terminated(opt(double), char(',')),
+        terminated(map_res(digit1, str::parse), char(',')),
+        md5,
+    ))(input)?;
+    let key = CacheKey(key.to_owned());
+    Ok((input, (key, CacheEntry { mtime, size, hash })))
+}
+
+/// Parse a single line of a cache file
+///
+/// This is the inverse of the `Display` implementation for `key,entry`. On failure,
+/// returns the 1-based column at which parsing stopped.
+pub fn parse_line(line: &str) -> Result<(CacheKey, CacheEntry), usize> {
+    match all_consuming(entry_line)(line).finish() {
+        Ok((_, res)) => Ok(res),
+        Err(e) => Err(line.len() - e.input.len() + 1),
+    }
 }
 
 impl Display for Cache {
@@ -99,3 +218,129 @@ impl Display for Cache {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{journal_path, parse_line, Cache, CacheEntry, CacheKey};
+    use crate::util::hash_file;
+
+    /// A hand-written sample in the format of the client's `quickcheck.txt`
+    const QUICKCHECK: &str = "\
+client\\legouniverse.exe,1311721836.000000,20111360,a4a53a5e1bc4b0d3a4b4cfe9a3aa2f52
+client\\res\\macros\\chat.scm,1311721840.000000,2410,67e13b4b7b8fd6bd58f2d8db0a13b3f1
+versions\\frontend.txt,,351774,e3d3db2aee3e4e2ac0d1c4dcb5f57fb8
+versions\\index.txt,1636829571.441830,412,0b8d1b1ea3e45ccc8b3d7e0e3ffe1f80
+";
+
+    #[test]
+    fn test_round_trip() {
+        let mut cache = Cache::new();
+        let skipped = cache.load_from(QUICKCHECK.as_bytes()).unwrap();
+        assert!(skipped.is_empty());
+        assert_eq!(cache.entries.len(), 4);
+        assert_eq!(cache.to_string(), QUICKCHECK);
+    }
+
+    #[test]
+    fn test_round_trip_files() {
+        // Record the files of this crate like the patcher records the client files
+        let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut cache = Cache::new();
+        for name in ["Cargo.toml", "README.md", "src/cache.rs"] {
+            let path = root.join(name);
+            let (size, hash) = hash_file(&path).unwrap();
+            let mtime = std::fs::metadata(&path)
+                .unwrap()
+                .modified()
+                .unwrap()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64();
+            let entry = CacheEntry {
+                mtime: Some(mtime),
+                size: size as u32,
+                hash,
+            };
+            cache.insert(CacheKey::new(name), entry);
+        }
+        let text = cache.to_string();
+
+        let mut loaded = Cache::new();
+        let skipped = loaded.load_from(text.as_bytes()).unwrap();
+        assert!(skipped.is_empty());
+        assert_eq!(loaded.entries.len(), 3);
+        assert_eq!(loaded.to_string(), text);
+    }
+
+    #[test]
+    fn test_crlf() {
+        let mut cache = Cache::new();
+        let input = QUICKCHECK.replace('\n', "\r\n");
+        let skipped = cache.load_from(input.as_bytes()).unwrap();
+        assert!(skipped.is_empty());
+        assert_eq!(cache.to_string(), QUICKCHECK);
+    }
+
+    #[test]
+    fn test_missing_mtime() {
+        let (key, entry) =
+            parse_line("versions\\trunk.txt,,12,0123456789abcdef0123456789abcdef").unwrap();
+        assert_eq!(key, CacheKey::new("versions/trunk.txt"));
+        assert_eq!(entry.mtime, None);
+        assert_eq!(entry.size, 12);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let input = "\
+client\\a.dll,1311721836.000000,100,a4a53a5e1bc4b0d3a4b4cfe9a3aa2f52
+client\\b.dll,1311721836.000000,1
+client\\c.dll,1311721836.000000,abc,a4a53a5e1bc4b0d3a4b4cfe9a3aa2f52
+
+client\\d.dll,garbage,100,a4a53a5e1bc4b0d3a4b4cfe9a3aa2f52
+client\\e.dll,1311721836.000000,100,a4a53a5e1bc4b0d3a4b4cfe9a3aa2f52,extra
+client\\f.dll,1311721836.000000,100,a4a53a5e1bc4b0d3a4b4cfe9a3aa2f5
+client\\g.dll,,100,a4a53a5e1bc4b0d3a4b4cfe9a3aa2f52
+";
+        let mut cache = Cache::new();
+        let skipped = cache.load_from(input.as_bytes()).unwrap();
+        let positions: Vec<_> = skipped.iter().map(|(l, c, _)| (*l, *c)).collect();
+        assert_eq!(positions, vec![(2, 33), (3, 32), (5, 14), (6, 68), (7, 36)]);
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(
+            cache.get(&CacheKey::new("client/g.dll")),
+            Some(&CacheEntry {
+                mtime: None,
+                size: 100,
+                hash: "a4a53a5e1bc4b0d3a4b4cfe9a3aa2f52".parse().unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_journal_replay() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("quickcheck.txt");
+        std::fs::write(&path, QUICKCHECK).unwrap();
+
+        // Simulate a run that is interrupted before `save`
+        let key = "client\\new.dll";
+        let hash = "0123456789abcdef0123456789abcdef".parse().unwrap();
+        let mut cache = Cache::open(&path).unwrap();
+        let entry = CacheEntry {
+            mtime: None,
+            size: 5,
+            hash,
+        };
+        cache.record(CacheKey::new(key), entry).unwrap();
+        drop(cache);
+
+        let cache = Cache::open(&path).unwrap();
+        assert_eq!(cache.entries.len(), 5);
+        assert_eq!(cache.get(&CacheKey::new(key)).map(|e| e.size), Some(5));
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text, cache.to_string());
+        assert_eq!(std::fs::metadata(journal_path(&path)).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,74 @@
+//! Graceful cancellation on Ctrl-C and `SIGTERM`
+
+use std::{fmt, future::Future};
+
+use log::warn;
+use tokio_util::sync::CancellationToken;
+
+/// The exit status of a run that was cancelled by a signal
+pub const EXIT_CANCELLED: i32 = 130;
+
+/// The run was cancelled by a signal
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+#[cfg(unix)]
+async fn terminate() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    signal(SignalKind::terminate())?.recv().await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn terminate() -> std::io::Result<()> {
+    std::future::pending().await
+}
+
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res,
+        res = terminate() => res,
+    }
+}
+
+/// Install the signal handlers
+///
+/// The first signal cancels the returned token, so that the patcher can stop
+/// scheduling new files and clean up. A second signal exits immediately.
+pub fn install() -> CancellationToken {
+    let token = CancellationToken::new();
+    let handle = token.clone();
+    tokio::spawn(async move {
+        if shutdown_signal().await.is_err() {
+            return;
+        }
+        warn!("Cancelling, send the signal again to exit immediately");
+        handle.cancel();
+        if shutdown_signal().await.is_ok() {
+            std::process::exit(EXIT_CANCELLED);
+        }
+    });
+    token
+}
+
+/// Run `fut` to completion, unless `token` is cancelled first
+///
+/// If the token is cancelled while `fut` is pending, the future is dropped.
+pub async fn or_cancel<F: Future>(
+    token: &CancellationToken,
+    fut: F,
+) -> Result<F::Output, Cancelled> {
+    tokio::select! {
+        biased;
+        _ = token.cancelled() => Err(Cancelled),
+        res = fut => Ok(res),
+    }
+}
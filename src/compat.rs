@@ -0,0 +1,175 @@
+//! Compatibility with the `patcherexeversion` a server requires
+//!
+//! The version names the build of the original patcher that the server was set up
+//! for. Newer builds may rely on behavior this patcher doesn't implement.
+
+use std::fmt;
+
+use log::{info, warn};
+
+/// How well this patcher supports a version of the original patcher
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Support {
+    /// Everything the server may use is implemented
+    Full,
+    /// Not in the table, so it may or may not work
+    Unknown,
+    /// The server requires behavior that isn't implemented
+    // No version in the table is known to need more yet
+    #[cfg_attr(not(test), allow(dead_code))]
+    Unsupported(&'static str),
+}
+
+/// A range of versions, from `min` (inclusive) to `max` (exclusive)
+struct Entry {
+    min: [u32; 4],
+    max: [u32; 4],
+    support: Support,
+}
+
+/// The known versions, in ascending order
+///
+/// Only add versions as unsupported together with the behavior that is
+/// missing, versions that are not listed produce a warning.
+const TABLE: &[Entry] = &[Entry {
+    min: [1, 0, 0, 0],
+    max: [2, 0, 0, 0],
+    support: Support::Full,
+}];
+
+/// A version that can't be parsed
+#[derive(Debug)]
+pub struct VersionError(String);
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid patcherexeversion {:?}", self.0)
+    }
+}
+
+impl std::error::Error for VersionError {}
+
+/// Parse a dotted version like `1.7.0.0`, missing parts are zero
+fn parse_version(input: &str) -> Result<[u32; 4], VersionError> {
+    let mut version = [0; 4];
+    let mut parts = input.trim().split('.');
+    for part in version.iter_mut() {
+        if let Some(p) = parts.next() {
+            *part = p.parse().map_err(|_| VersionError(input.to_owned()))?;
+        }
+    }
+    if parts.next().is_some() {
+        return Err(VersionError(input.to_owned()));
+    }
+    Ok(version)
+}
+
+/// Look up the support for `version` in `table`
+fn support_in(table: &[Entry], version: &str) -> Result<Support, VersionError> {
+    let version = parse_version(version)?;
+    let entry = table.iter().find(|e| e.min <= version && version < e.max);
+    Ok(entry.map_or(Support::Unknown, |e| e.support))
+}
+
+/// Look up the support for `version` in the table
+pub fn support(version: &str) -> Result<Support, VersionError> {
+    support_in(TABLE, version)
+}
+
+/// A `patcherexeversion` that this patcher doesn't implement
+#[derive(Debug)]
+pub struct Incompatible {
+    version: String,
+    reason: &'static str,
+}
+
+impl fmt::Display for Incompatible {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The server requires patcher version {} ({}), use --ignore-patcher-version to continue anyway",
+            self.version, self.reason
+        )
+    }
+}
+
+impl std::error::Error for Incompatible {}
+
+/// Check whether the `patcherexeversion` from the config is supported
+///
+/// Versions that are not in the table produce a warning, ones that are
+/// unsupported produce an error unless `force` is set.
+pub fn check(version: &str, force: bool) -> Result<(), Incompatible> {
+    check_in(TABLE, version, force)
+}
+
+/// [`check`] against `table`
+fn check_in(table: &[Entry], version: &str, force: bool) -> Result<(), Incompatible> {
+    if version.is_empty() {
+        warn!("The server doesn't specify a patcherexeversion");
+        return Ok(());
+    }
+    match support_in(table, version) {
+        Ok(Support::Full) => info!("Patcher version {} is supported", version),
+        Ok(Support::Unknown) => warn!(
+            "Patcher version {} is unknown to this patcher, some files may not be patched correctly",
+            version
+        ),
+        Ok(Support::Unsupported(reason)) if force => {
+            warn!(
+                "Ignoring unsupported patcher version {}: {}",
+                version, reason
+            )
+        }
+        Ok(Support::Unsupported(reason)) => {
+            return Err(Incompatible {
+                version: version.to_owned(),
+                reason,
+            })
+        }
+        Err(e) => warn!("{}, assuming it is supported", e),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_in, support, Entry, Support};
+
+    #[test]
+    fn test_support() {
+        assert_eq!(support("1.7.0.0").unwrap(), Support::Full);
+        assert_eq!(support("1.0").unwrap(), Support::Full);
+        assert_eq!(support("0.9.1").unwrap(), Support::Unknown);
+        assert_eq!(support("2.0.0.0").unwrap(), Support::Unknown);
+        assert!(support("1.x").is_err());
+        assert!(support("1.2.3.4.5").is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let table = &[
+            Entry {
+                min: [1, 0, 0, 0],
+                max: [2, 0, 0, 0],
+                support: Support::Full,
+            },
+            Entry {
+                min: [2, 0, 0, 0],
+                max: [3, 0, 0, 0],
+                support: Support::Unsupported("new compression"),
+            },
+        ];
+        check_in(table, "1.7.0.0", false).unwrap();
+        check_in(table, "3.0", false).unwrap();
+        check_in(table, "", false).unwrap();
+        check_in(table, "1.x", false).unwrap();
+
+        let err = check_in(table, "2.1", false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The server requires patcher version 2.1 (new compression), use --ignore-patcher-version to continue anyway"
+        );
+        check_in(table, "2.1", true).unwrap();
+    }
+}
@@ -1,4 +1,13 @@
-use std::{convert::Infallible, fmt::Display, path::PathBuf, str::FromStr};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Default, Clone)]
 pub struct ExcludeList {
@@ -6,6 +15,18 @@ pub struct ExcludeList {
     paths: Vec<PathBuf>,
 }
 
+impl Display for ExcludeList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, path) in self.paths.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
 impl FromStr for ExcludeList {
     type Err = Infallible;
 
@@ -44,8 +65,125 @@ pub struct PatcherConfig {
     pub waitstart: bool,
     pub usedefaultinstallpath: bool,
     pub usedynamicdownload: bool,
+    /// Keys this patcher doesn't know about, qualified by their section
+    pub extra: BTreeMap<String, String>,
+    /// The local override files the overridden values of each key came from
+    ///
+    /// `noclean` has one file for each entry added by an override, other keys
+    /// only the last override file.
+    pub overrides: BTreeMap<String, Vec<PathBuf>>,
+    /// The file this config was parsed from
+    document: Document,
 }
 
+/// Where the value of a key came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin<'a> {
+    /// Not set anywhere
+    Default,
+    /// The `patcher.ini` from the server
+    Server,
+    /// A local override file
+    Override(&'a Path),
+}
+
+impl Display for Origin<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::Server => write!(f, "server"),
+            Self::Override(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// The client variants the patcher can install
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    /// The small client from `minimalmanifestfile`, which fetches the rest while playing
+    Minimal,
+    /// The full client from `defaultmanifestfile`
+    Default,
+}
+
+impl Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Minimal => write!(f, "minimal"),
+            Self::Default => write!(f, "default"),
+        }
+    }
+}
+
+impl FromStr for Variant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "minimal" => Ok(Self::Minimal),
+            "default" => Ok(Self::Default),
+            _ => Err(format!("expected minimal or default, got {:?}", s)),
+        }
+    }
+}
+
+/// One line of a `patcher.ini` file
+#[derive(Debug, Clone)]
+enum Line {
+    /// A comment, section header, blank or unparseable line
+    Text(String),
+    /// A `key=value` line
+    Entry {
+        /// The key, qualified by its section for unknown keys
+        name: String,
+        /// The key as written in the file
+        key: String,
+        /// The parsed value
+        value: String,
+        /// The line as written in the file
+        raw: String,
+    },
+}
+
+/// The lines of a `patcher.ini` file, used to write it back losslessly
+#[derive(Debug, Default, Clone)]
+struct Document {
+    lines: Vec<Line>,
+    crlf: bool,
+}
+
+/// The known keys, in the order they are usually written
+pub const KEYS: &[&str] = &[
+    "patcherexeversion",
+    "serverdirectory",
+    "downloaddirectory",
+    "patcherdirectory",
+    "installerdirectory",
+    "versionfile",
+    "indexfile",
+    "defaultmanifestfile",
+    "minimalmanifestfile",
+    "hotfixmanifestfile",
+    "packcatalog",
+    "defaultinstallpath",
+    "installkey",
+    "installfile",
+    "configfile",
+    "win_exclude",
+    "mac_exclude",
+    "noclean",
+    "caption",
+    "cachefile",
+    "check",
+    "quickcheck",
+    "clean",
+    "log",
+    "waitstart",
+    "usedefaultinstallpath",
+    "usedynamicdownload",
+];
+
 impl std::default::Default for PatcherConfig {
     fn default() -> Self {
         Self {
@@ -90,79 +228,366 @@ impl std::default::Default for PatcherConfig {
             waitstart: true,
             usedefaultinstallpath: true,
             usedynamicdownload: true,
+            extra: BTreeMap::new(),
+            overrides: BTreeMap::new(),
+            document: Document::default(),
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum Error {
-    UnknownKey(String),
+    /// A `=value` line without a key
+    MissingKey(usize),
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::UnknownKey(k) => write!(f, "Unknown key '{}'", k),
+            Self::MissingKey(line) => write!(f, "line {}: Missing key", line),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
-impl From<Infallible> for Error {
-    fn from(_: Infallible) -> Self {
-        unreachable!()
-    }
-}
-
 fn is_true(s: &str) -> bool {
     matches!(s, "Yes" | "True")
 }
 
+fn fmt_bool(b: bool) -> String {
+    if b { "Yes" } else { "No" }.to_string()
+}
+
+impl PatcherConfig {
+    /// Set a known key, returning `false` if this patcher doesn't know it
+    ///
+    /// `noclean` may be given multiple times, each call adds an entry.
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "patcherexeversion" => self.patcherexeversion = value.to_owned(),
+            "serverdirectory" => self.serverdirectory = value.to_owned(),
+            "downloaddirectory" => self.downloaddirectory = value.to_owned(),
+            "patcherdirectory" => self.patcherdirectory = value.to_owned(),
+            "installerdirectory" => self.installerdirectory = value.to_owned(),
+            "versionfile" => self.versionfile = value.to_owned(),
+            "indexfile" => self.indexfile = value.to_owned(),
+            "defaultmanifestfile" => self.defaultmanifestfile = value.to_owned(),
+            "minimalmanifestfile" => self.minimalmanifestfile = value.to_owned(),
+            "hotfixmanifestfile" => self.hotfixmanifestfile = value.to_owned(),
+            "packcatalog" => self.packcatalog = value.to_owned(),
+            "defaultinstallpath" => self.defaultinstallpath = value.to_owned(),
+            "installkey" => self.installkey = value.to_owned(),
+            "installfile" => self.installfile = value.to_owned(),
+            "configfile" => self.configfile = value.to_owned(),
+            "win_exclude" => self.win_exclude = value.parse().unwrap(),
+            "mac_exclude" => self.mac_exclude = value.parse().unwrap(),
+            "noclean" => self.noclean.push(PathBuf::from(value)),
+            "caption" => self.caption = value.to_owned(),
+            "cachefile" => self.cachefile = value.to_owned(),
+            "check" => self.check = is_true(value),
+            "quickcheck" => self.quickcheck = is_true(value),
+            "clean" => self.clean = is_true(value),
+            "log" => self.log = is_true(value),
+            "waitstart" => self.waitstart = is_true(value),
+            "usedefaultinstallpath" => self.usedefaultinstallpath = is_true(value),
+            "usedynamicdownload" => self.usedynamicdownload = is_true(value),
+            _ => return false,
+        }
+        true
+    }
+
+    /// The manifest that lists the files of `variant`
+    pub fn manifest_file(&self, variant: Variant) -> &str {
+        match variant {
+            Variant::Minimal => &self.minimalmanifestfile,
+            Variant::Default => &self.defaultmanifestfile,
+        }
+    }
+
+    /// All values of a key, formatted as in the file
+    ///
+    /// This is a single value for all keys except `noclean`, unknown keys are
+    /// looked up in [`PatcherConfig::extra`].
+    pub fn values(&self, key: &str) -> Option<Vec<String>> {
+        let value = match key {
+            "patcherexeversion" => self.patcherexeversion.clone(),
+            "serverdirectory" => self.serverdirectory.clone(),
+            "downloaddirectory" => self.downloaddirectory.clone(),
+            "patcherdirectory" => self.patcherdirectory.clone(),
+            "installerdirectory" => self.installerdirectory.clone(),
+            "versionfile" => self.versionfile.clone(),
+            "indexfile" => self.indexfile.clone(),
+            "defaultmanifestfile" => self.defaultmanifestfile.clone(),
+            "minimalmanifestfile" => self.minimalmanifestfile.clone(),
+            "hotfixmanifestfile" => self.hotfixmanifestfile.clone(),
+            "packcatalog" => self.packcatalog.clone(),
+            "defaultinstallpath" => self.defaultinstallpath.clone(),
+            "installkey" => self.installkey.clone(),
+            "installfile" => self.installfile.clone(),
+            "configfile" => self.configfile.clone(),
+            "win_exclude" => self.win_exclude.to_string(),
+            "mac_exclude" => self.mac_exclude.to_string(),
+            "noclean" => {
+                let paths = self.noclean.iter();
+                return Some(paths.map(|p| p.display().to_string()).collect());
+            }
+            "caption" => self.caption.clone(),
+            "cachefile" => self.cachefile.clone(),
+            "check" => fmt_bool(self.check),
+            "quickcheck" => fmt_bool(self.quickcheck),
+            "clean" => fmt_bool(self.clean),
+            "log" => fmt_bool(self.log),
+            "waitstart" => fmt_bool(self.waitstart),
+            "usedefaultinstallpath" => fmt_bool(self.usedefaultinstallpath),
+            "usedynamicdownload" => fmt_bool(self.usedynamicdownload),
+            _ => return self.extra.get(key).map(|v| vec![v.clone()]),
+        };
+        Some(vec![value])
+    }
+
+    /// Apply the keys from a local override file at `path`
+    ///
+    /// Keys replace the values from this config, except for `noclean` entries,
+    /// which are added to the existing ones.
+    pub fn merge(&mut self, local: &PatcherConfig, path: &Path) {
+        for line in &local.document.lines {
+            if let Line::Entry { name, value, .. } = line {
+                if !self.set(name, value) {
+                    self.extra.insert(name.clone(), value.clone());
+                }
+                let paths = self.overrides.entry(name.clone()).or_default();
+                if name != "noclean" {
+                    paths.clear();
+                }
+                paths.push(path.to_owned());
+            }
+        }
+    }
+
+    /// The keys with different values in `other`, with the old and new values
+    pub fn diff(&self, other: &PatcherConfig) -> Vec<(String, Vec<String>, Vec<String>)> {
+        let mut keys: Vec<&str> = KEYS.to_vec();
+        let extra = self.extra.keys().chain(other.extra.keys());
+        for key in extra.map(String::as_str) {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        keys.into_iter()
+            .filter_map(|key| {
+                let old = self.values(key).unwrap_or_default();
+                let new = other.values(key).unwrap_or_default();
+                (old != new).then(|| (key.to_owned(), old, new))
+            })
+            .collect()
+    }
+
+    /// Where each of the [`PatcherConfig::values`] of `key` came from
+    ///
+    /// The values added by overrides come after those from the server.
+    pub fn origins(&self, key: &str) -> Vec<Origin<'_>> {
+        let count = self.values(key).map_or(0, |values| values.len());
+        let overrides = self.overrides.get(key).map_or(&[][..], Vec::as_slice);
+        let local = overrides.len().min(count);
+        let in_file = self
+            .document
+            .lines
+            .iter()
+            .filter(|line| matches!(line, Line::Entry { name, .. } if name == key))
+            .count();
+        let server = (0..count - local).map(|index| {
+            if index < in_file {
+                Origin::Server
+            } else {
+                Origin::Default
+            }
+        });
+        let local = overrides[overrides.len() - local..]
+            .iter()
+            .map(|path| Origin::Override(path));
+        server.chain(local).collect()
+    }
+
+    /// Write the config in `patcher.ini` format
+    ///
+    /// Lines of the parsed file are kept in order, including comments, and are
+    /// only rewritten if their value changed. Values that are not in the file
+    /// and differ from the defaults are added at the end.
+    pub fn to_ini(&self) -> String {
+        let defaults = Self::default();
+        let mut written: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut lines = Vec::new();
+        for line in &self.document.lines {
+            match line {
+                Line::Text(raw) => lines.push(raw.clone()),
+                Line::Entry {
+                    name,
+                    key,
+                    value,
+                    raw,
+                } => {
+                    let index = written.entry(name).or_default();
+                    let values = self.values(name).unwrap_or_default();
+                    match values.get(*index) {
+                        Some(v) if v == value => lines.push(raw.clone()),
+                        Some(v) => lines.push(format!("{}={}", key, v)),
+                        None => {}
+                    }
+                    *index += 1;
+                }
+            }
+        }
+
+        let extra = self.extra.keys().map(String::as_str);
+        for name in KEYS.iter().copied().chain(extra) {
+            let index = written.get(name).copied().unwrap_or_default();
+            let values = self.values(name).unwrap_or_default();
+            if index == 0 && defaults.values(name) == Some(values.clone()) {
+                continue;
+            }
+            for value in values.iter().skip(index) {
+                lines.push(format!("{}={}", name, value));
+            }
+        }
+
+        let newline = if self.document.crlf { "\r\n" } else { "\n" };
+        let mut output = lines.join(newline);
+        output.push_str(newline);
+        output
+    }
+}
+
 impl FromStr for PatcherConfig {
     type Err = Error;
 
+    /// Parse a `patcher.ini` file
+    ///
+    /// Unknown keys are kept in [`PatcherConfig::extra`] with a warning. Keys of the
+    /// patcher are recognized in any section, unknown keys in a section are stored
+    /// as `section.key`.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut cfg = Self::default();
-        for line in s.split('\n') {
-            if line.starts_with('#') {
-                continue;
-            }
-            if let Some((key, value)) = line.split_once('=') {
+        let mut section: Option<String> = None;
+        cfg.document.crlf = s.contains("\r\n");
+        let s = s.strip_suffix('\n').unwrap_or(s);
+        for (index, raw) in s.split('\n').enumerate() {
+            let raw = raw.strip_suffix('\r').unwrap_or(raw);
+            let line = raw.trim();
+            let line_no = index + 1;
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                cfg.document.lines.push(Line::Text(raw.to_owned()));
+            } else if let Some(header) = line.strip_prefix('[') {
+                match header.strip_suffix(']') {
+                    Some(name) => section = Some(name.trim().to_owned()),
+                    None => warn!("patcher.ini:{}: unterminated section {:?}", line_no, line),
+                }
+                cfg.document.lines.push(Line::Text(raw.to_owned()));
+            } else if let Some((key, value)) = line.split_once('=') {
+                let key = key.trim();
+                if key.is_empty() {
+                    return Err(Error::MissingKey(line_no));
+                }
                 let value = value.trim();
                 let value = value.trim_matches('"');
-                match key {
-                    "patcherexeversion" => cfg.patcherexeversion = value.parse()?,
-                    "serverdirectory" => cfg.serverdirectory = value.parse()?,
-                    "downloaddirectory" => cfg.downloaddirectory = value.parse()?,
-                    "patcherdirectory" => cfg.patcherdirectory = value.parse()?,
-                    "installerdirectory" => cfg.installerdirectory = value.parse()?,
-                    "versionfile" => cfg.versionfile = value.parse()?,
-                    "indexfile" => cfg.indexfile = value.parse()?,
-                    "defaultmanifestfile" => cfg.defaultmanifestfile = value.parse()?,
-                    "minimalmanifestfile" => cfg.minimalmanifestfile = value.parse()?,
-                    "hotfixmanifestfile" => cfg.hotfixmanifestfile = value.parse()?,
-                    "packcatalog" => cfg.packcatalog = value.parse()?,
-                    "defaultinstallpath" => cfg.defaultinstallpath = value.parse()?,
-                    "installkey" => cfg.installkey = value.parse()?,
-                    "installfile" => cfg.installfile = value.parse()?,
-                    "configfile" => cfg.configfile = value.parse()?,
-                    "win_exclude" => cfg.win_exclude = value.parse()?,
-                    "mac_exclude" => cfg.mac_exclude = value.parse()?,
-                    "noclean" => cfg.noclean.push(PathBuf::from(value)),
-                    "caption" => cfg.caption = value.parse()?,
-                    "cachefile" => cfg.cachefile = value.parse()?,
-                    "check" => cfg.check = is_true(value),
-                    "quickcheck" => cfg.quickcheck = is_true(value),
-                    "clean" => cfg.clean = is_true(value),
-                    "log" => cfg.log = is_true(value),
-                    "waitstart" => cfg.waitstart = is_true(value),
-                    "usedefaultinstallpath" => cfg.usedefaultinstallpath = is_true(value),
-                    "usedynamicdownload" => cfg.usedynamicdownload = is_true(value),
-                    _ => return Err(Error::UnknownKey(key.to_owned())),
-                }
+                let name = if cfg.set(key, value) {
+                    key.to_owned()
+                } else {
+                    let name = match &section {
+                        Some(section) => format!("{}.{}", section, key),
+                        None => key.to_owned(),
+                    };
+                    warn!("patcher.ini:{}: unknown key '{}'", line_no, name);
+                    cfg.extra.insert(name.clone(), value.to_owned());
+                    name
+                };
+                cfg.document.lines.push(Line::Entry {
+                    name,
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                    raw: raw.to_owned(),
+                });
+            } else {
+                warn!("patcher.ini:{}: ignoring {:?}", line_no, line);
+                cfg.document.lines.push(Line::Text(raw.to_owned()));
             }
         }
         Ok(cfg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Origin, PatcherConfig};
+    use std::path::Path;
+
+    const PATCHER_INI: &str = "\
+# LEGO Universe patcher configuration\r
+[patcher]\r
+patcherexeversion=1.7.0.0\r
+downloaddirectory = \"versions\"\r
+installfile=lego_universe_install.exe\r
+noclean=client/res/macros\r
+noclean=client/boot.cfg\r
+; local additions\r
+clean=Yes\r
+mirror=cdn2.example.com\r
+\r
+";
+
+    #[test]
+    fn test_round_trip() {
+        let cfg: PatcherConfig = PATCHER_INI.parse().unwrap();
+        assert_eq!(cfg.patcherexeversion, "1.7.0.0");
+        assert_eq!(cfg.downloaddirectory, "versions");
+        assert_eq!(cfg.noclean.len(), 2);
+        assert_eq!(cfg.extra["patcher.mirror"], "cdn2.example.com");
+        assert_eq!(cfg.to_ini(), PATCHER_INI);
+    }
+
+    #[test]
+    fn test_modified() {
+        let mut cfg: PatcherConfig = PATCHER_INI.parse().unwrap();
+        cfg.clean = false;
+        cfg.set("noclean", "client/locale");
+        cfg.set("indexfile", "index2.txt");
+        let expected = PATCHER_INI.replace("clean=Yes", "clean=No").replace(
+            "\r\n\r\n",
+            "\r\n\r\nindexfile=index2.txt\r\nnoclean=client/locale\r\n",
+        );
+        assert_eq!(cfg.to_ini(), expected);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut cfg: PatcherConfig = PATCHER_INI.parse().unwrap();
+        let local: PatcherConfig =
+            "clean=No\nnoclean=client/locale\ndefaultmanifestfile=frontend.txt\n"
+                .parse()
+                .unwrap();
+        let path = Path::new("lux-patcher.ini");
+        cfg.merge(&local, path);
+        assert!(!cfg.clean);
+        assert_eq!(cfg.noclean.len(), 3);
+        assert_eq!(cfg.defaultmanifestfile, "frontend.txt");
+        assert_eq!(cfg.origins("clean"), [Origin::Override(path)]);
+        assert_eq!(cfg.origins("installfile"), [Origin::Server]);
+        assert_eq!(cfg.origins("indexfile"), [Origin::Default]);
+        assert_eq!(
+            cfg.origins("noclean"),
+            [Origin::Server, Origin::Server, Origin::Override(path)]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        // An unterminated section is kept, and the keys after it are still read
+        let text = "[patcher]\n[other\nquickcheck=No\n";
+        let cfg: PatcherConfig = text.parse().unwrap();
+        assert!(!cfg.quickcheck);
+        assert_eq!(cfg.to_ini(), text);
+
+        let err = "check=Yes\n\n =Yes\n".parse::<PatcherConfig>().unwrap_err();
+        assert_eq!(err.to_string(), "line 3: Missing key");
+    }
+}
@@ -22,7 +22,7 @@ pub fn calculate_crc(path: &[u8]) -> u32 {
         if b == b'/' {
             b = b'\\';
         }
-        if (b'A'..=b'Z').contains(&b) {
+        if b.is_ascii_uppercase() {
             b += b'a' - b'A';
         }
 
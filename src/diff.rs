@@ -0,0 +1,128 @@
+//! Comparing two versions of a manifest
+
+use assembly_pack::txt::{FileLine, Manifest};
+
+/// How a file differs between two manifests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Removed,
+    /// The content (hash) changed
+    Changed,
+}
+
+/// One file that differs between two manifests
+#[derive(Debug, PartialEq)]
+pub struct Entry<'a> {
+    pub path: &'a str,
+    pub change: Change,
+    /// The uncompressed size in the old manifest
+    pub old_size: Option<u32>,
+    /// The uncompressed size in the new manifest
+    pub new_size: Option<u32>,
+    /// The bytes to download to get the new version of the file
+    pub download: u64,
+}
+
+impl Entry<'_> {
+    /// The change of the uncompressed size
+    pub fn size_delta(&self) -> i64 {
+        i64::from(self.new_size.unwrap_or(0)) - i64::from(self.old_size.unwrap_or(0))
+    }
+}
+
+/// The files that differ between two manifests, by path
+pub fn diff<'a>(old: &'a Manifest, new: &'a Manifest) -> Vec<Entry<'a>> {
+    let mut entries = Vec::new();
+    let entry = |path: &'a str, change, old: Option<&FileLine>, new: Option<&FileLine>| Entry {
+        path,
+        change,
+        old_size: old.map(|f| f.filesize),
+        new_size: new.map(|f| f.filesize),
+        download: new.map_or(0, |f| u64::from(f.compressed_filesize)),
+    };
+    for (path, f) in &old.files {
+        match new.files.get(path) {
+            None => entries.push(entry(path, Change::Removed, Some(f), None)),
+            Some(n) if n.hash != f.hash => {
+                entries.push(entry(path, Change::Changed, Some(f), Some(n)))
+            }
+            Some(_) => {}
+        }
+    }
+    for (path, f) in &new.files {
+        if !old.files.contains_key(path) {
+            entries.push(entry(path, Change::Added, None, Some(f)));
+        }
+    }
+    entries.sort_by_key(|e| e.path);
+    entries
+}
+
+/// Print the entries and a summary with the total download cost
+pub fn print(old: &Manifest, new: &Manifest, entries: &[Entry]) {
+    println!(
+        "{} (version {}) -> {} (version {})",
+        old.version.name, old.version.version, new.version.name, new.version.version
+    );
+    let mut counts = [0; 3];
+    for e in entries {
+        let (sign, index) = match e.change {
+            Change::Added => ('+', 0),
+            Change::Removed => ('-', 1),
+            Change::Changed => ('~', 2),
+        };
+        counts[index] += 1;
+        println!("{} {} ({:+} bytes)", sign, e.path, e.size_delta());
+    }
+    let size_delta: i64 = entries.iter().map(Entry::size_delta).sum();
+    let download: u64 = entries.iter().map(|e| e.download).sum();
+    println!(
+        "{} added, {} removed, {} changed, {:+} bytes installed, {} bytes to download",
+        counts[0], counts[1], counts[2], size_delta, download
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, Change};
+    use crate::manifest::load_manifest;
+
+    const OLD: &str = "\
+[version]
+1,00000000000000000000000000000001,old
+[files]
+client/a.txt,10,00000000000000000000000000000010,8,00000000000000000000000000000011,00000000000000000000000000000000
+client/b.txt,20,00000000000000000000000000000020,15,00000000000000000000000000000021,00000000000000000000000000000000
+client/c.txt,30,00000000000000000000000000000030,25,00000000000000000000000000000031,00000000000000000000000000000000
+";
+
+    const NEW: &str = "\
+[version]
+2,00000000000000000000000000000002,new
+[files]
+client/a.txt,10,00000000000000000000000000000010,8,00000000000000000000000000000011,00000000000000000000000000000000
+client/b.txt,24,00000000000000000000000000000022,18,00000000000000000000000000000023,00000000000000000000000000000000
+client/d.txt,40,00000000000000000000000000000040,35,00000000000000000000000000000041,00000000000000000000000000000000
+";
+
+    #[tokio::test]
+    async fn test_diff() {
+        let old = load_manifest(OLD.as_bytes()).await.unwrap();
+        let new = load_manifest(NEW.as_bytes()).await.unwrap();
+        let entries = diff(&old, &new);
+
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| (e.path, e.change, e.size_delta(), e.download))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("client/b.txt", Change::Changed, 4, 18),
+                ("client/c.txt", Change::Removed, -30, 0),
+                ("client/d.txt", Change::Added, 40, 35),
+            ]
+        );
+    }
+}
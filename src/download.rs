@@ -1,74 +1,269 @@
-use std::{fs::File, io::BufWriter, path::Path};
+use std::path::{Path, PathBuf};
 
-use assembly_pack::sd0::read::SegmentedDecoder;
-use color_eyre::eyre::Context;
-use futures_util::TryStreamExt;
-use log::info;
+use assembly_pack::{md5::MD5Sum, sd0::MAGIC, txt::FileLine};
+use color_eyre::eyre::{eyre, Context};
+use futures_util::{StreamExt, TryStreamExt};
+use log::{info, warn};
 use reqwest::Url;
-use tokio::io::AsyncBufRead;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
 use tokio_util::io::StreamReader;
 
-use crate::util::into_io_error;
+use crate::{
+    ratelimit::RateLimit,
+    sd0,
+    store::BlobStore,
+    util::{into_io_error, with_suffix},
+};
 
-async fn stream_to_file<S>(path: &Path, mut bytes: &mut S) -> color_eyre::Result<()>
+/// A temporary file next to its final path, which is removed unless persisted
+struct PartFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl PartFile {
+    fn new(target: &Path) -> Self {
+        Self {
+            path: with_suffix(target, ".part"),
+            persisted: false,
+        }
+    }
+
+    /// Atomically replace `target` with this file
+    fn persist(mut self, target: &Path) -> std::io::Result<()> {
+        std::fs::rename(&self.path, target)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Check the size and hash of decompressed data against the manifest
+fn verify(f: &FileLine, size: u64, hash: MD5Sum) -> color_eyre::Result<()> {
+    if size != u64::from(f.filesize) {
+        return Err(eyre!("Expected {} bytes, got {}", f.filesize, size));
+    }
+    if hash != f.hash {
+        return Err(eyre!("Expected hash {}, got {}", f.hash, hash));
+    }
+    Ok(())
+}
+
+/// Whether the manifest marks `f` as stored without compression on the server
+fn is_uncompressed(f: &FileLine) -> bool {
+    f.compressed_filesize == f.filesize && f.compressed_hash == f.hash
+}
+
+/// How a payload is stored on the patch server
+#[derive(Debug, PartialEq)]
+enum Encoding {
+    /// The decompressed file, as marked in the manifest
+    Raw,
+    /// Compressed with sd0
+    Sd0,
+}
+
+/// Find the encoding of the payload for `f` that starts with `prefix`
+fn encoding(f: &FileLine, prefix: &[u8]) -> Option<Encoding> {
+    if is_uncompressed(f) {
+        Some(Encoding::Raw)
+    } else if prefix == MAGIC {
+        Some(Encoding::Sd0)
+    } else {
+        None
+    }
+}
+
+/// Fill `buf` from `reader`, stopping early only at the end of the stream
+async fn read_prefix<R>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize>
 where
-    S: AsyncBufRead + Unpin,
+    R: AsyncRead + Unpin,
 {
-    let mut file = tokio::fs::File::create(path).await?;
-    tokio::io::copy(&mut bytes, &mut file).await?;
-    Ok(())
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]).await? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
 }
 
-fn decompress_sd0(input: &Path, output: &Path) -> color_eyre::Result<()> {
-    let file = File::open(input)?;
-    let mut buf = std::io::BufReader::new(file);
-    let mut reader = SegmentedDecoder::new(&mut buf)?;
+/// Copy `reader` to `writer` verbatim
+///
+/// Returns the size and MD5 hash of the data.
+async fn copy_raw<R, W>(reader: &mut R, writer: &mut W) -> std::io::Result<(u64, MD5Sum)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut context = md5::Context::new();
+    let mut size = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        context.consume(&buf[..len]);
+        writer.write_all(&buf[..len]).await?;
+        size += len as u64;
+    }
+    writer.flush().await?;
+    Ok((size, MD5Sum(context.compute().0)))
+}
 
-    let out = File::create(output)?;
-    let mut writer = BufWriter::new(out);
+/// Whether `path` in the download directory is a temporary file
+///
+/// These are the `.part` files of downloads, the `.link` files of
+/// [`crate::util::link_or_copy`] and the `.tmp` files of atomic saves, which
+/// are only recognized next to the file they would have replaced.
+fn is_partial(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("part" | "link") => true,
+        Some("tmp") => path.with_extension("").is_file(),
+        _ => false,
+    }
+}
 
-    std::io::copy(&mut reader, &mut writer).context("Streaming sd0 file")?;
-    Ok(())
+/// Remove the temporary files that an interrupted run left behind
+///
+/// These are the `.part` and `.link` files next to each of `files`, and the
+/// temporary and compressed files at the top of the download directory,
+/// `download_dir`, which only the patcher writes to. Returns the number of
+/// files that were removed.
+pub fn remove_partial<I>(download_dir: &Path, files: I) -> std::io::Result<usize>
+where
+    I: IntoIterator<Item = PathBuf>,
+{
+    let mut count = 0;
+    let mut remove = |path: &Path| {
+        info!("removing {}", path.display());
+        std::fs::remove_file(path)?;
+        count += 1;
+        std::io::Result::Ok(())
+    };
+    if download_dir.is_dir() {
+        for entry in std::fs::read_dir(download_dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let is_sd0 = path.extension() == Some("sd0".as_ref());
+            if entry.file_type()?.is_file() && (is_sd0 || is_partial(&path)) {
+                remove(&path)?;
+            }
+        }
+    }
+    for file in files {
+        for suffix in [".part", ".link"] {
+            let path = with_suffix(&file, suffix);
+            if path.is_file() {
+                remove(&path)?;
+            }
+        }
+    }
+    Ok(count)
 }
 
 pub struct Downloader {
     client: reqwest::Client,
+    store: Option<BlobStore>,
+    limit: RateLimit,
 }
 
 impl Downloader {
     pub fn new() -> Self {
         Self {
             client: reqwest::Client::new(),
+            store: None,
+            limit: RateLimit::new(None),
         }
     }
 
-    pub async fn download(
-        &self,
-        url: Url,
-        download_dir: &Path,
-        path: &Path,
-    ) -> color_eyre::Result<()> {
-        let mut sd0_filename = path.file_name().unwrap().to_owned();
-        sd0_filename.push(".sd0");
-        let sd0_path = download_dir.join(sd0_filename);
-        info!("saving to {}", sd0_path.display());
-
-        // Stream the compressed file to disk
-        let mut byte_stream = self.get_bytes_tokio(url).await?;
-        stream_to_file(&sd0_path, &mut byte_stream).await?;
+    /// The blob store that downloads are shared through, if any
+    pub fn store(&self) -> Option<&BlobStore> {
+        self.store.as_ref()
+    }
 
-        info!("download complete, decompressing to {}", path.display());
+    /// The bandwidth limit for all downloads of this downloader
+    pub fn rate_limit(&self) -> &RateLimit {
+        &self.limit
+    }
+
+    /// Share the downloaded files with other installs through `store`
+    pub fn with_store(mut self, store: BlobStore) -> Self {
+        self.store = Some(store);
+        self
+    }
 
+    /// Download the file at `url` and decompress it to `path`
+    ///
+    /// The payload is copied verbatim if the manifest marks it as uncompressed,
+    /// and decoded if it starts with the sd0 magic bytes. The data is streamed
+    /// to a sibling file and only moved into place once it matches the size and
+    /// hash from `f`, so `path` is never left half-written.
+    ///
+    /// With a blob store, the file is taken from the store if it is there, and
+    /// added to it otherwise.
+    pub async fn download(&self, url: Url, f: &FileLine, path: &Path) -> color_eyre::Result<()> {
         // Create the parent folder
         let output_dir = path.parent().unwrap();
         tokio::fs::create_dir_all(output_dir).await?;
 
-        // Decompress the file
-        decompress_sd0(&sd0_path, path)?;
+        if let Some(store) = &self.store {
+            let (store, target) = (store.clone(), path.to_owned());
+            let f = FileLine { ..*f };
+            if tokio::task::spawn_blocking(move || store.fetch(&f, &target)).await?? {
+                return Ok(());
+            }
+        }
+
+        let mut byte_stream = self.get_bytes_tokio(url).await?;
+        let mut magic = [0u8; 5];
+        let len = read_prefix(&mut byte_stream, &mut magic).await?;
+        let encoding = encoding(f, &magic[..len]).ok_or_else(|| {
+            eyre!(
+                "Payload for {} is neither sd0 compressed nor marked as uncompressed (starts with {:?})",
+                path.display(),
+                &magic[..len]
+            )
+        })?;
+        let mut reader = (&magic[..len]).chain(byte_stream);
+
+        let part = PartFile::new(path);
+        let file = tokio::fs::File::create(&part.path).await?;
+        let mut writer = BufWriter::new(file);
+        let (size, hash) = match encoding {
+            Encoding::Raw => {
+                info!("copying to {}", part.path.display());
+                copy_raw(&mut reader, &mut writer)
+                    .await
+                    .context("Streaming uncompressed file")?
+            }
+            Encoding::Sd0 => {
+                info!("decompressing to {}", part.path.display());
+                sd0::decode(&mut reader, &mut writer, u64::from(f.filesize))
+                    .await
+                    .context("Streaming sd0 file")?
+            }
+        };
+        verify(f, size, hash).wrap_err_with(|| format!("Failed to verify {}", path.display()))?;
+        writer.into_inner().sync_all().await?;
 
-        info!("removing compressed file");
-        std::fs::remove_file(&sd0_path)
-            .wrap_err_with(|| format!("Failed to remove {}", sd0_path.display()))?;
+        info!("download complete, moving to {}", path.display());
+        part.persist(path)?;
+
+        if let Some(store) = &self.store {
+            if let Err(e) = store.insert(f, path) {
+                warn!("Failed to add {} to the blob store: {}", path.display(), e);
+            }
+        }
         Ok(())
     }
 
@@ -86,8 +281,102 @@ impl Downloader {
         &self,
         url: Url,
     ) -> color_eyre::Result<impl tokio::io::AsyncBufRead> {
-        let stream = self.get(url).await?.bytes_stream().map_err(into_io_error);
-        let reader = StreamReader::new(stream);
+        let limit = self.limit.clone();
+        let stream = self
+            .get(url)
+            .await?
+            .bytes_stream()
+            .map_err(into_io_error)
+            .then(move |chunk| {
+                let limit = limit.clone();
+                async move {
+                    if let Ok(bytes) = &chunk {
+                        limit.acquire(bytes.len()).await;
+                    }
+                    chunk
+                }
+            });
+        let reader = StreamReader::new(Box::pin(stream));
         Ok(reader)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_raw, encoding, remove_partial, Encoding};
+    use assembly_pack::{md5::MD5Sum, sd0::MAGIC, txt::FileLine};
+
+    const DATA: &[u8] = b"Hello World!\n";
+
+    /// The manifest line for `DATA`, stored either raw or compressed
+    fn file_line(uncompressed: bool) -> FileLine {
+        let hash = MD5Sum(md5::compute(DATA).0);
+        let (compressed_filesize, compressed_hash) = match uncompressed {
+            true => (DATA.len() as u32, hash),
+            false => (30, MD5Sum([0; 16])),
+        };
+        FileLine {
+            filesize: DATA.len() as u32,
+            hash,
+            compressed_filesize,
+            compressed_hash,
+            line_hash: hash,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_raw() {
+        let mut output = Vec::new();
+        let (size, hash) = copy_raw(&mut &DATA[..], &mut output).await.unwrap();
+        assert_eq!(output, DATA);
+        assert_eq!(size, 13);
+        assert_eq!(hash.0, md5::compute(DATA).0);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let raw = file_line(true);
+        let compressed = file_line(false);
+        assert_eq!(encoding(&raw, &DATA[..5]), Some(Encoding::Raw));
+        assert_eq!(encoding(&raw, MAGIC), Some(Encoding::Raw));
+        assert_eq!(encoding(&compressed, MAGIC), Some(Encoding::Sd0));
+        assert_eq!(encoding(&compressed, &DATA[..5]), None);
+        assert_eq!(encoding(&compressed, &MAGIC[..3]), None);
+    }
+
+    #[test]
+    fn test_remove_partial() {
+        let tmp = tempfile::tempdir().unwrap();
+        let install = tmp.path().join("client");
+        let download = install.join("download");
+        let res = install.join("res");
+        std::fs::create_dir_all(&download).unwrap();
+        std::fs::create_dir_all(res.join("other")).unwrap();
+
+        let leftovers = [
+            download.join("0a1b2c.sd0"),
+            download.join("quickcheck.txt.tmp"),
+            download.join("trunk.txt.part"),
+            res.join("a.fdb.part"),
+            res.join("b.dll.link"),
+        ];
+        let kept = [
+            download.join("quickcheck.txt"),
+            res.join("a.fdb"),
+            res.join("c.tmp"),
+            res.join("d.sd0"),
+            // Not a file of the manifest
+            res.join("e.fdb.part"),
+            res.join("other").join("f.dll.link"),
+        ];
+        for path in leftovers.iter().chain(&kept) {
+            std::fs::write(path, b"").unwrap();
+        }
+
+        let files = ["a.fdb", "b.dll", "c", "d"].map(|name| res.join(name));
+        let count = remove_partial(&download, files).unwrap();
+        assert_eq!(count, leftovers.len());
+        assert!(leftovers.iter().all(|path| !path.exists()));
+        assert!(kept.iter().all(|path| path.exists()));
+    }
+}
@@ -0,0 +1,177 @@
+//! The registry of installs managed by this tool
+
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Variant,
+    universe::Source,
+    util::{data_dir, into_io_error, print_table, with_suffix},
+};
+
+/// Where an install loads the universe config from, see [`Source`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvSource {
+    Service(String),
+    File(PathBuf),
+    Cached,
+}
+
+impl EnvSource {
+    /// Store `source`, with a file relative to the current directory
+    pub fn new(source: Source<'_>) -> std::io::Result<Self> {
+        Ok(match source {
+            Source::Service(cfg_url) => Self::Service(cfg_url.to_owned()),
+            Source::File(path) => Self::File(std::env::current_dir()?.join(path)),
+            Source::Cached => Self::Cached,
+        })
+    }
+
+    pub fn as_source(&self) -> Source<'_> {
+        match self {
+            Self::Service(cfg_url) => Source::Service(cfg_url),
+            Self::File(path) => Source::File(path),
+            Self::Cached => Source::Cached,
+        }
+    }
+}
+
+/// The choices made for one install, so that it can be updated without prompts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Install {
+    pub install_dir: PathBuf,
+    /// The environment to look the universe up in
+    pub env: String,
+    pub env_source: EnvSource,
+    pub universe: String,
+    pub variant: Variant,
+    /// The version of the version file at the last successful update
+    pub version: Option<u32>,
+    /// The cache file of the install, which lists the files it uses
+    #[serde(default)]
+    pub cache_file: Option<PathBuf>,
+}
+
+/// A small JSON database of installs, by name
+#[derive(Debug, Default)]
+pub struct Registry {
+    installs: BTreeMap<String, Install>,
+}
+
+/// Where the registry is stored by default
+pub fn default_path() -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("installs.json"))
+}
+
+impl Registry {
+    /// Load the registry at `path`, which is empty if the file doesn't exist
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let installs = serde_json::from_str(&text).map_err(into_io_error)?;
+        Ok(Self { installs })
+    }
+
+    /// Write the registry to `path`, replacing the old file atomically
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let text = serde_json::to_string_pretty(&self.installs).map_err(into_io_error)?;
+        let tmp_path = with_suffix(path, ".tmp");
+        std::fs::write(&tmp_path, text)?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Install> {
+        self.installs.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Install> {
+        self.installs.get_mut(name)
+    }
+
+    /// Add an install, returning `false` if the name is already taken
+    pub fn add(&mut self, name: &str, install: Install) -> bool {
+        if self.installs.contains_key(name) {
+            return false;
+        }
+        self.installs.insert(name.to_owned(), install);
+        true
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Install)> {
+        self.installs.iter()
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Install> {
+        self.installs.remove(name)
+    }
+
+    /// Print all installs as an aligned table
+    pub fn print(&self) {
+        let header = ["NAME", "ENV", "UNIVERSE", "VARIANT", "VERSION", "DIR"];
+        let rows: Vec<[String; 6]> = self
+            .installs
+            .iter()
+            .map(|(name, i)| {
+                [
+                    name.clone(),
+                    i.env.clone(),
+                    i.universe.clone(),
+                    i.variant.to_string(),
+                    i.version
+                        .map_or_else(|| String::from("-"), |v| v.to_string()),
+                    i.install_dir.display().to_string(),
+                ]
+            })
+            .collect();
+        print_table(header, &rows);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EnvSource, Install, Registry};
+    use crate::config::Variant;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("installs.json");
+
+        let mut registry = Registry::load(&path).unwrap();
+        let install = Install {
+            install_dir: PathBuf::from("/games/lu-test"),
+            env: String::from("test"),
+            env_source: EnvSource::File(PathBuf::from("/games/EnvironmentInfo.xml")),
+            universe: String::from("Overbuild Universe (US)"),
+            variant: Variant::Minimal,
+            version: None,
+            cache_file: None,
+        };
+        assert!(registry.add("test", install.clone()));
+        assert!(!registry.add("test", install.clone()));
+        registry.get_mut("test").unwrap().version = Some(42);
+        registry.save(&path).unwrap();
+
+        let mut copy = Registry::load(&path).unwrap();
+        let stored = copy.get("test").unwrap();
+        assert_eq!(stored.version, Some(42));
+        assert_eq!(stored.variant, Variant::Minimal);
+        assert_eq!(stored.env_source, install.env_source);
+        assert!(copy.remove("test").is_some());
+        assert!(copy.get("test").is_none());
+    }
+}
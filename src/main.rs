@@ -1,79 +1,683 @@
-use std::{
-    convert::TryFrom,
-    path::{Path, PathBuf},
-};
+use std::path::{Path, PathBuf};
 
 use argh::FromArgs;
-use assembly_pack::pki::core::PackIndexFile;
-use assembly_xml::universe_config::Environment;
+use assembly_pack::txt::{Manifest, VersionLine};
+use assembly_xml::universe_config::{Environment, Server};
 use color_eyre::eyre::{eyre, Context};
 use log::{info, warn};
 use manifest::load_manifest;
 use reqwest::Url;
 use terminal_menu::{button, label, menu, mut_menu, run};
 
-use crate::{cache::Cache, download::Downloader, patcher::PatcherBuilder};
+use crate::{
+    boot::{BootConfig, Format, Tokens},
+    cache::Cache,
+    cancel::{Cancelled, EXIT_CANCELLED},
+    config::{PatcherConfig, Variant},
+    download::Downloader,
+    installs::{EnvSource, Install, Registry},
+    patcher::{Patcher, PatcherBuilder, Pin},
+    store::BlobStore,
+    universe::UniverseRow,
+    winpath::{DriveMap, WinPath},
+};
 
 mod boot;
 mod cache;
+mod cancel;
+mod compat;
 mod config;
 mod crc;
+mod diff;
 mod download;
+mod installs;
 mod manifest;
 mod patcher;
+mod ratelimit;
+mod sd0;
+mod space;
+mod store;
+mod universe;
 mod util;
+mod winpath;
 
 fn live() -> String {
     String::from("live")
 }
 
+fn parse_var(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, value)) => Ok((name.to_owned(), value.to_owned())),
+        None => Err(format!("expected name=value, got {:?}", value)),
+    }
+}
+
 #[derive(FromArgs)]
 /// Run the LU patcher
 struct Options {
     /// the base URL of the patch server
     #[argh(option)]
-    cfg_url: String,
+    cfg_url: Option<String>,
 
     /// the base URL of the patch server
     #[argh(option, default = "live()")]
     env: String,
 
+    /// use the universe config from the last run instead of fetching it
+    #[argh(switch)]
+    use_cached_env: bool,
+
+    /// load the universe config from a local EnvironmentInfo XML file
+    #[argh(option)]
+    env_file: Option<PathBuf>,
+
     /// the installation directory
     #[argh(option)]
     install_dir: Option<PathBuf>,
+
+    /// set the value of a placeholder in the patcher config (name=value)
+    #[argh(option, from_str_fn(parse_var))]
+    var: Vec<(String, String)>,
+
+    /// resolve Windows paths on a drive or share from a local directory
+    /// (e.g. C=/mnt/c or \\server\share=/mnt/share)
+    #[argh(option, from_str_fn(parse_var))]
+    map_drive: Vec<(String, String)>,
+
+    /// continue even if the server requires an unsupported patcher version
+    #[argh(switch)]
+    ignore_patcher_version: bool,
+
+    /// patch without checking for free disk space first
+    #[argh(switch)]
+    ignore_disk_space: bool,
+
+    /// print the versions of the server files and exit
+    #[argh(switch)]
+    print_server_info: bool,
+
+    /// download the installer into the download directory
+    #[argh(switch)]
+    fetch_installer: bool,
+
+    /// print the size and hash of the installer and exit
+    #[argh(switch)]
+    installer_info: bool,
+
+    /// the format of the boot config: cfg (written to the configfile), json
+    /// or toml (printed)
+    #[argh(option, default = "Format::Cfg")]
+    boot_format: Format,
+
+    /// override a key of the boot config (KEY=VALUE)
+    #[argh(option, from_str_fn(parse_var))]
+    boot_set: Vec<(String, String)>,
+
+    /// share downloaded files with other installs through this directory
+    #[argh(option)]
+    blob_store: Option<PathBuf>,
+
+    /// update an install that was pinned to a version by rollback
+    #[argh(switch)]
+    unpin: bool,
+
+    /// limit the download bandwidth in bytes per second (e.g. 500k or 2M)
+    #[argh(option, from_str_fn(ratelimit::parse_rate))]
+    limit_rate: Option<u64>,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main(flavor = "multi_thread")]
-async fn main() -> color_eyre::Result<()> {
-    pretty_env_logger::formatted_builder()
-        .filter_module("lux_patcher", log::LevelFilter::Info)
-        .init();
-    let args: Options = argh::from_env();
+impl Options {
+    /// The local directories for Windows drives and shares
+    fn drives(&self) -> color_eyre::Result<DriveMap> {
+        let mut drives = DriveMap::default();
+        for (from, to) in &self.map_drive {
+            drives
+                .insert(from, PathBuf::from(to))
+                .map_err(|e| eyre!("Invalid --map-drive: {}", e))?;
+        }
+        Ok(drives)
+    }
 
-    // Create client
-    let net = Downloader::new();
+    /// Where to load the universe config from
+    fn env_source(&self) -> color_eyre::Result<universe::Source<'_>> {
+        if let Some(path) = &self.env_file {
+            Ok(universe::Source::File(path))
+        } else if self.use_cached_env {
+            Ok(universe::Source::Cached)
+        } else {
+            let cfg_url = self.cfg_url.as_deref();
+            let cfg_url =
+                cfg_url.ok_or_else(|| eyre!("One of --cfg-url or --env-file is required"))?;
+            Ok(universe::Source::Service(cfg_url))
+        }
+    }
+}
 
-    // Cleanup base parameter
-    let options = Url::options();
-    let api = Url::parse(&args.cfg_url)?;
-    let base_url = options.base_url(Some(&api));
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    BootCfg(BootCfgCommand),
+    Config(ConfigCommand),
+    Diff(DiffCommand),
+    Gc(GcCommand),
+    Installs(InstallsCommand),
+    Rollback(RollbackCommand),
+    Universes(UniversesCommand),
+}
 
-    // Get universe config
-    let mut env_info_url = base_url.parse("UniverseConfig.svc/xml/EnvironmentInfo")?;
+#[derive(FromArgs)]
+/// List the universes of one or more environments
+#[argh(subcommand, name = "universes")]
+struct UniversesCommand {
+    /// an environment to list (repeatable, defaults to the global --env)
+    #[argh(option)]
+    env: Vec<String>,
 
-    info!("Environment: {}", &args.env);
-    let env_query = format!("environment={}", &args.env);
-    env_info_url.set_query(Some(&env_query));
+    /// print as JSON instead of a table
+    #[argh(switch)]
+    json: bool,
+}
 
-    info!("Loading {}", env_info_url);
+/// Print the servers of all requested environments
+async fn list_universes(
+    net: &Downloader,
+    args: &Options,
+    cmd: &UniversesCommand,
+) -> color_eyre::Result<()> {
+    let envs = if cmd.env.is_empty() {
+        std::slice::from_ref(&args.env)
+    } else {
+        &cmd.env[..]
+    };
+    let mut infos = Vec::new();
+    for env in envs {
+        let env_info = universe::load(net, args.env_source()?, env).await?;
+        infos.push((env.as_str(), env_info));
+    }
 
-    // Get the environment info
-    let env_info_xml = net.get_text(env_info_url).await?;
-    let env_info: Environment = assembly_xml::quick::de::from_str(&env_info_xml)?;
+    let rows: Vec<UniverseRow> = infos
+        .iter()
+        .flat_map(|(env, info)| {
+            info.servers
+                .servers
+                .iter()
+                .map(move |s| UniverseRow::new(env, s))
+        })
+        .collect();
+    if cmd.json {
+        println!("{}", serde_json::to_string_pretty(&rows)?);
+    } else {
+        universe::print_universes(&rows);
+    }
+    Ok(())
+}
 
-    info!("Found {} universe(s)", env_info.servers.servers.len());
+#[derive(FromArgs)]
+/// Write boot.cfg for a universe, without patching the game files
+#[argh(subcommand, name = "boot-cfg")]
+struct BootCfgCommand {
+    /// the name of the universe
+    #[argh(option)]
+    universe: Option<String>,
+
+    /// start from a JSON or TOML boot config instead of a universe
+    #[argh(option)]
+    template: Option<PathBuf>,
+
+    /// the client variant to configure: minimal or default
+    #[argh(option, default = "Variant::Default")]
+    variant: Variant,
+
+    /// write to this file instead of the configfile from the patcher config
+    #[argh(option)]
+    output: Option<PathBuf>,
+}
+
+/// Find the path of `boot.cfg` from the `configfile` setting
+fn boot_cfg_path(
+    configfile: &str,
+    tokens: &Tokens,
+    drives: &DriveMap,
+) -> color_eyre::Result<PathBuf> {
+    let configfile = tokens.resolve(configfile)?;
+    info!("Config file: {:?}", configfile);
+    Ok(WinPath::parse(&configfile).to_native(drives)?)
+}
+
+/// Write `config` in `format` to `path`, or print it if there is no path
+async fn write_boot_cfg(
+    config: &BootConfig,
+    format: Format,
+    path: Option<&Path>,
+) -> color_eyre::Result<()> {
+    let text = config.render(format)?;
+    match path {
+        Some(path) => tokio::fs::write(path, text)
+            .await
+            .wrap_err_with(|| eyre!("Failed to write {}", path.display()))?,
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+/// Write `boot.cfg` for the universe or template selected in `cmd`
+///
+/// For a universe, this only needs the bootstrap patcher config, the catalog
+/// is used if it was downloaded by an earlier run. A template does not need
+/// the network at all.
+async fn boot_cfg(
+    net: &Downloader,
+    args: &Options,
+    cmd: &BootCfgCommand,
+) -> color_eyre::Result<()> {
+    let format = args.boot_format;
+    let (boot, configfile) = match (&cmd.universe, &cmd.template) {
+        (Some(name), None) => {
+            let env_info = universe::load(net, args.env_source()?, &args.env).await?;
+            let server = universe::find_server(&env_info, name)?;
+            info!("Selected: {}", server.name);
+
+            let mut builder = PatcherBuilder::setup(net, &server.cdn_info)
+                .await?
+                .with_drives(args.drives()?);
+            let dirs = builder.resolve(args.install_dir.as_deref())?;
+            let config = &builder.config;
+
+            let catalog_file = dirs.download.join(&config.packcatalog);
+            let pki = if catalog_file.exists() {
+                patcher::load_pki(&catalog_file)?
+            } else {
+                patcher::empty_pki()
+            };
+            let boot =
+                BootConfig::new(server, &env_info, config, cmd.variant, &pki, &args.boot_set)?;
+            let tokens = patcher::tokens(config, &dirs, &server.language, &args.var);
+            let path = boot_cfg_path(&config.configfile, &tokens, &dirs.drives)?;
+            (boot, Some(path))
+        }
+        (None, Some(path)) => {
+            let template_format = Format::from_path(path).unwrap_or(Format::Json);
+            let text = std::fs::read_to_string(path)
+                .wrap_err_with(|| eyre!("Failed to read {}", path.display()))?;
+            let mut boot = BootConfig::parse(&text, template_format)
+                .wrap_err_with(|| eyre!("Failed to load {}", path.display()))?;
+            for (key, value) in &args.boot_set {
+                boot.set(key, value)?;
+            }
+            (boot, None)
+        }
+        _ => return Err(eyre!("Exactly one of --universe or --template is required")),
+    };
+
+    // Only replace the client's boot.cfg with a file it can read
+    let path = match (&cmd.output, configfile) {
+        (Some(output), _) => Some(output.clone()),
+        (None, Some(configfile)) if format == Format::Cfg => Some(configfile),
+        _ => None,
+    };
+    write_boot_cfg(&boot, format, path.as_deref()).await
+}
+
+#[derive(FromArgs)]
+/// Manage the registry of installs
+#[argh(subcommand, name = "installs")]
+struct InstallsCommand {
+    #[argh(subcommand)]
+    action: InstallsAction,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum InstallsAction {
+    List(InstallsList),
+    Add(InstallsAdd),
+    Update(InstallsUpdate),
+    Remove(InstallsRemove),
+}
+
+#[derive(FromArgs)]
+/// List the registered installs
+#[argh(subcommand, name = "list")]
+struct InstallsList {}
+
+#[derive(FromArgs)]
+/// Register the install in --install-dir for the global --env and --cfg-url
+#[argh(subcommand, name = "add")]
+struct InstallsAdd {
+    /// the name of the install
+    #[argh(positional)]
+    name: String,
+
+    /// the name of the universe
+    #[argh(option)]
+    universe: String,
+
+    /// the client variant: minimal or default
+    #[argh(option, default = "Variant::Default")]
+    variant: Variant,
+}
+
+#[derive(FromArgs)]
+/// Patch a registered install with its stored choices, without prompts
+#[argh(subcommand, name = "update")]
+struct InstallsUpdate {
+    /// the name of the install
+    #[argh(positional)]
+    name: String,
+}
+
+#[derive(FromArgs)]
+/// Remove an install from the registry, keeping its files
+#[argh(subcommand, name = "remove")]
+struct InstallsRemove {
+    /// the name of the install
+    #[argh(positional)]
+    name: String,
+}
+
+/// Where the installs registry is stored
+fn registry_path() -> color_eyre::Result<PathBuf> {
+    installs::default_path().ok_or_else(|| eyre!("No data directory for this user"))
+}
+
+/// Load the installs registry, with the path to save it to
+fn load_registry() -> color_eyre::Result<(PathBuf, Registry)> {
+    let path = registry_path()?;
+    let registry =
+        Registry::load(&path).wrap_err_with(|| eyre!("Failed to load {}", path.display()))?;
+    Ok((path, registry))
+}
+
+fn save_registry(path: &Path, registry: &Registry) -> color_eyre::Result<()> {
+    registry
+        .save(path)
+        .wrap_err_with(|| eyre!("Failed to write {}", path.display()))
+}
+
+/// Register the install in `--install-dir` with the global options
+async fn add_install(
+    net: &Downloader,
+    args: &Options,
+    add: &InstallsAdd,
+) -> color_eyre::Result<()> {
+    let (path, mut registry) = load_registry()?;
+    let install_dir = args
+        .install_dir
+        .as_deref()
+        .ok_or_else(|| eyre!("--install-dir is required to add an install"))?;
+    let mut dir = std::env::current_dir()?;
+    util::join(&mut dir, install_dir);
+
+    let env_source = EnvSource::new(args.env_source()?)?;
+    let env_info = universe::load(net, env_source.as_source(), &args.env).await?;
+    let server = universe::find_server(&env_info, &add.universe)?;
+
+    // The cache file lists the files of the install for `gc`
+    let mut builder = PatcherBuilder::setup(net, &server.cdn_info)
+        .await?
+        .with_drives(args.drives()?);
+    let dirs = builder.resolve(Some(&dir))?;
+    let cache_file = dirs.download.join(&builder.config.cachefile);
+
+    let install = Install {
+        install_dir: dir,
+        env: args.env.clone(),
+        env_source,
+        universe: server.name.clone(),
+        variant: add.variant,
+        version: None,
+        cache_file: Some(cache_file),
+    };
+    if !registry.add(&add.name, install) {
+        return Err(eyre!("An install named {:?} already exists", add.name));
+    }
+    save_registry(&path, &registry)
+}
+
+/// Remove an install from the registry, but not its files
+fn remove_install(remove: &InstallsRemove) -> color_eyre::Result<()> {
+    let (path, mut registry) = load_registry()?;
+    if registry.remove(&remove.name).is_none() {
+        return Err(eyre!("No install named {:?}", remove.name));
+    }
+    save_registry(&path, &registry)
+}
+
+#[derive(FromArgs)]
+/// Compare two manifests, e.g. before rolling out a server update
+#[argh(subcommand, name = "diff")]
+struct DiffCommand {
+    /// the old manifest, a local file or a name on the patch server
+    #[argh(positional)]
+    old: String,
+
+    /// the new manifest, a local file or a name on the patch server
+    #[argh(positional)]
+    new: String,
+
+    /// the universe to fetch manifests from, if they are not local files
+    #[argh(option)]
+    universe: Option<String>,
+}
+
+/// Load a manifest for `diff` from a local file, or by name from the server
+async fn diff_source(
+    net: &Downloader,
+    remote: Option<&PatcherBuilder>,
+    source: &str,
+    dir: &Path,
+) -> color_eyre::Result<Manifest> {
+    let path = Path::new(source);
+    if path.is_file() {
+        let file = tokio::fs::File::open(path)
+            .await
+            .wrap_err_with(|| eyre!("Failed to open {}", path.display()))?;
+        return load_manifest(tokio::io::BufReader::new(file)).await;
+    }
+    let remote = remote.ok_or_else(|| {
+        eyre!(
+            "{} is not a file, use --universe to fetch it from the patch server",
+            source
+        )
+    })?;
+    remote.fetch_manifest(net, source, dir).await
+}
+
+/// Print the differences between two manifests
+async fn diff_manifests(
+    net: &Downloader,
+    args: &Options,
+    cmd: &DiffCommand,
+) -> color_eyre::Result<()> {
+    let remote = match &cmd.universe {
+        Some(name) => {
+            let env_info = universe::load(net, args.env_source()?, &args.env).await?;
+            let server = universe::find_server(&env_info, name)?;
+            let mut builder = PatcherBuilder::setup(net, &server.cdn_info)
+                .await?
+                .with_drives(args.drives()?);
+            builder.resolve(args.install_dir.as_deref())?;
+            Some(builder)
+        }
+        None => None,
+    };
+
+    let dir = std::env::temp_dir().join(format!("lux-patcher-diff-{}", std::process::id()));
+    let res = async {
+        let old = diff_source(net, remote.as_ref(), &cmd.old, &dir).await?;
+        let new = diff_source(net, remote.as_ref(), &cmd.new, &dir).await?;
+        Ok::<_, color_eyre::Report>((old, new))
+    }
+    .await;
+    let _ = std::fs::remove_dir_all(&dir);
+    let (old, new) = res?;
+
+    let entries = diff::diff(&old, &new);
+    diff::print(&old, &new, &entries);
+    Ok(())
+}
+
+#[derive(FromArgs)]
+/// Patch the install back to a version it had before and pin it there
+#[argh(subcommand, name = "rollback")]
+struct RollbackCommand {
+    /// the version, as saved by an earlier update
+    #[argh(positional)]
+    version: u32,
+
+    /// the base URL of a mirror that still has the files of that version
+    #[argh(option)]
+    mirror: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// Remove the files from --blob-store that no registered install uses
+#[argh(subcommand, name = "gc")]
+struct GcCommand {}
+
+/// Prune the blob store, keeping the files in the caches of all installs
+///
+/// These are the registered installs and the ones that were patched with the
+/// store. This refuses to run if the cache file of a registered install is not
+/// known, as all of its files would be removed.
+fn gc(args: &Options) -> color_eyre::Result<()> {
+    let root = args
+        .blob_store
+        .clone()
+        .ok_or_else(|| eyre!("--blob-store is required for gc"))?;
+    let store = BlobStore::new(root);
+    let (_, registry) = load_registry()?;
+    let mut cache_files = Vec::new();
+    for (name, install) in registry.iter() {
+        match &install.cache_file {
+            Some(cache_file) => cache_files.push((name.clone(), cache_file.clone())),
+            None => {
+                return Err(eyre!(
+                    "The cache file of {:?} is unknown, run `installs update {}` before gc",
+                    name,
+                    name
+                ))
+            }
+        }
+    }
+    for cache_file in store.caches()? {
+        if !cache_files.iter().any(|(_, path)| path == &cache_file) {
+            cache_files.push((cache_file.display().to_string(), cache_file));
+        }
+    }
+
+    let mut keep = Vec::new();
+    for (name, cache_file) in &cache_files {
+        let mut cache = Cache::new();
+        cache.load(cache_file)?;
+        cache.load(&cache::journal_path(cache_file))?;
+        info!("{} uses {} file(s)", name, cache.hashes().count());
+        keep.extend(cache.hashes());
+    }
+    let (count, bytes) = store.gc(keep)?;
+    info!("Removed {} blob(s), {} bytes", count, bytes);
+    Ok(())
+}
+
+#[derive(FromArgs)]
+/// Inspect the patcher config
+#[argh(subcommand, name = "config")]
+struct ConfigCommand {
+    #[argh(subcommand)]
+    action: ConfigAction,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum ConfigAction {
+    Show(ConfigShow),
+}
+
+#[derive(FromArgs)]
+/// Print the effective config, after applying local overrides
+#[argh(subcommand, name = "show")]
+struct ConfigShow {
+    /// the name of the universe, instead of picking it from a menu
+    #[argh(option)]
+    universe: Option<String>,
+
+    /// print in patcher.ini format, without origins
+    #[argh(switch)]
+    ini: bool,
+}
+
+/// Print the versions of the meta files on the server, for support requests
+async fn print_server_info(
+    patcher: &Patcher,
+    cache: &mut Cache,
+    versions: &Manifest,
+    server: &Server,
+) -> color_eyre::Result<()> {
+    let config = &patcher.config;
+    patcher
+        .ensure_meta(cache, versions, &config.indexfile)
+        .await?;
+    let index = patcher.load_manifest(&config.indexfile).await?;
+
+    println!("universe: {}", server.name);
+    println!("patch server: {}", patcher.url);
+    let support = match compat::support(&config.patcherexeversion) {
+        Ok(support) => format!("{:?}", support),
+        Err(e) => e.to_string(),
+    };
+    println!(
+        "patcherexeversion: {} ({})",
+        config.patcherexeversion, support
+    );
+
+    let print_version = |file: &str, v: &VersionLine| {
+        println!("{}: version {} ({}) {}", file, v.version, v.name, v.hash);
+    };
+    print_version(&config.versionfile, &versions.version);
+    print_version(&config.indexfile, &index.version);
+    for file in [&config.defaultmanifestfile, &config.minimalmanifestfile] {
+        if patcher.ensure_meta(cache, &index, file).await? {
+            let manifest = patcher.load_manifest(file).await?;
+            print_version(file, &manifest.version);
+        } else {
+            println!("{}: missing", file);
+        }
+    }
+    match index.files.get(&config.packcatalog) {
+        Some(f) => println!("{}: {} bytes {}", config.packcatalog, f.filesize, f.hash),
+        None => println!("{}: missing", config.packcatalog),
+    }
+    Ok(())
+}
 
-    // Present the universe selection menu
+/// Print each key of the config with its value and where it came from
+fn show_config(
+    mut builder: PatcherBuilder,
+    install_dir: Option<&Path>,
+    cmd: &ConfigShow,
+) -> color_eyre::Result<()> {
+    let dirs = builder.resolve(install_dir)?;
+    let config = &builder.config;
+    if cmd.ini {
+        print!("{}", config.to_ini());
+        return Ok(());
+    }
+    println!("# install dir: {}", dirs.install.display());
+    let extra = config.extra.keys().map(String::as_str);
+    for key in config::KEYS.iter().copied().chain(extra) {
+        let values = config.values(key).unwrap_or_default();
+        for (value, origin) in values.iter().zip(config.origins(key)) {
+            println!("{}={:<40} # {}", key, value, origin);
+        }
+    }
+    Ok(())
+}
+
+/// Let the user pick a universe from a menu
+fn select_universe(env_info: &Environment) -> &Server {
     let label_iter = Some(label("Select a universe:")).into_iter();
     let button_iter = env_info.servers.servers.iter().map(|s| &s.name).map(button);
     let buttons = label_iter.chain(button_iter).collect();
@@ -83,14 +687,110 @@ async fn main() -> color_eyre::Result<()> {
 
     // you can get the selected buttons name like so:
     let sel = mut_menu(&universe_menu).selected_item_index() - 1; // -1 for the label
-    let server = &env_info.servers.servers[sel];
+    &env_info.servers.servers[sel]
+}
+
+/// Let the user pick the client variant from a menu
+fn select_variant(config: &PatcherConfig) -> Variant {
+    let variant_menu = menu(vec![
+        button(format!("Minimal ({})", config.minimalmanifestfile)),
+        button(format!("Default ({})", config.defaultmanifestfile)),
+    ]);
+
+    run(&variant_menu);
+
+    let sel = mut_menu(&variant_menu).selected_item_index();
+    if sel == 0 {
+        Variant::Minimal
+    } else {
+        Variant::Default
+    }
+}
+
+#[tokio::main(flavor = "multi_thread")]
+async fn main() -> color_eyre::Result<()> {
+    pretty_env_logger::formatted_builder()
+        .filter_module("lux_patcher", log::LevelFilter::Info)
+        .init();
+    let mut args: Options = argh::from_env();
+
+    // Create client
+    let mut net = Downloader::new();
+    if let Some(root) = &args.blob_store {
+        net = net.with_store(BlobStore::new(root.clone()));
+    }
+    if let Some(rate) = args.limit_rate {
+        net.rate_limit().set(Some(rate));
+    }
+
+    if let Some(Command::Universes(cmd)) = &args.command {
+        return list_universes(&net, &args, cmd).await;
+    }
+    if let Some(Command::BootCfg(cmd)) = &args.command {
+        return boot_cfg(&net, &args, cmd).await;
+    }
+    if let Some(Command::Diff(cmd)) = &args.command {
+        return diff_manifests(&net, &args, cmd).await;
+    }
+    if let Some(Command::Gc(_)) = &args.command {
+        return gc(&args);
+    }
+
+    // Use the stored choices when updating a registered install
+    let mut registered = None;
+    if let Some(Command::Installs(cmd)) = &args.command {
+        let name = match &cmd.action {
+            InstallsAction::List(_) => return load_registry().map(|(_, r)| r.print()),
+            InstallsAction::Add(add) => return add_install(&net, &args, add).await,
+            InstallsAction::Remove(remove) => return remove_install(remove),
+            InstallsAction::Update(update) => update.name.clone(),
+        };
+        let (_, registry) = load_registry()?;
+        let install = registry
+            .get(&name)
+            .ok_or_else(|| eyre!("No install named {:?}", name))?
+            .clone();
+        info!("Updating {} in {}", name, install.install_dir.display());
+        args.env = install.env.clone();
+        args.install_dir = Some(install.install_dir.clone());
+        registered = Some((name, install));
+    }
+
+    let env_source = match &registered {
+        Some((_, install)) => install.env_source.as_source(),
+        None => args.env_source()?,
+    };
+    let env_info = universe::load(&net, env_source, &args.env).await?;
+
+    info!("Found {} universe(s)", env_info.servers.servers.len());
+
+    let universe = match (&registered, &args.command) {
+        (Some((_, install)), _) => Some(install.universe.as_str()),
+        (None, Some(Command::Config(cmd))) => match &cmd.action {
+            ConfigAction::Show(show) => show.universe.as_deref(),
+        },
+        _ => None,
+    };
+    let server = match universe {
+        Some(name) => universe::find_server(&env_info, name)?,
+        None => select_universe(&env_info),
+    };
 
     info!("Selected: {}", server.name);
     info!("{:?}", server.cdn_info);
 
-    let patcher_builder = PatcherBuilder::setup(&net, &server.cdn_info).await?;
+    let patcher_builder = PatcherBuilder::setup(&net, &server.cdn_info)
+        .await?
+        .with_drives(args.drives()?);
     let install_dir = args.install_dir.as_deref();
-    let patcher = patcher_builder.build(net, install_dir)?;
+
+    if let Some(Command::Config(cmd)) = &args.command {
+        match &cmd.action {
+            ConfigAction::Show(show) => return show_config(patcher_builder, install_dir, show),
+        }
+    }
+    let mut patcher = patcher_builder.build(net, install_dir)?;
+    patcher.check_space = !args.ignore_disk_space;
 
     let version_url = patcher.url.join(&patcher.config.versionfile)?;
     info!("Version file: {}", version_url);
@@ -98,136 +798,186 @@ async fn main() -> color_eyre::Result<()> {
     let byte_stream = patcher.net.get_bytes_tokio(version_url).await?;
     let versions = load_manifest(byte_stream).await?;
 
+    // Keep a pinned install at its version, including its patcher config
+    if args.unpin && patcher.unpin()? {
+        info!("Removed the pin, updating to the current version");
+    }
+    let rollback = match (&args.command, patcher.pinned()?) {
+        (Some(Command::Rollback(cmd)), _) => Some(Pin {
+            version: cmd.version,
+            mirror: cmd.mirror.clone(),
+        }),
+        (_, Some(pin)) => {
+            info!(
+                "The install is pinned to version {}, use --unpin to update it",
+                pin.version
+            );
+            Some(pin)
+        }
+        (_, None) => None,
+    };
+
     let patcher_config_key = patcher.config_key();
-    if let Some(f) = versions.files.get(&patcher_config_key) {
+    if let Some(pin) = &rollback {
+        patcher.restore_config(pin.version)?;
+    } else if let Some(f) = versions.files.get(&patcher_config_key) {
         let patcher_config_url = patcher.get_url(f)?;
         info!("patcher config is {}", patcher_config_url);
 
         let patcher_config_path = patcher.dirs.install.join(patcher_config_key);
         patcher
             .net
-            .download(
-                patcher_config_url,
-                &patcher.dirs.download,
-                &patcher_config_path,
-            )
+            .download(patcher_config_url, f, &patcher_config_path)
             .await?;
+        patcher.reload_config(&patcher_config_path)?;
     } else {
         warn!("patcher config {:?} not found", patcher_config_key);
     }
 
-    let install_file_key = patcher.install_file_key();
-    if let Some(f) = versions.files.get(&install_file_key) {
-        info!("installer is {:?} (ignoring)", &f.hash);
+    if !args.print_server_info {
+        compat::check(
+            &patcher.config.patcherexeversion,
+            args.ignore_patcher_version,
+        )?;
     }
 
-    let mut cache = Cache::new();
     let cache_path = patcher.dirs.download.join(&patcher.config.cachefile);
+    let mut cache = Cache::open(&cache_path)
+        .wrap_err_with(|| eyre!("Failed to open {}", cache_path.display()))?;
+
+    if let Some(root) = &args.blob_store {
+        BlobStore::new(root.clone())
+            .add_cache(&cache_path)
+            .wrap_err_with(|| {
+                eyre!(
+                    "Failed to register {} in the blob store",
+                    cache_path.display()
+                )
+            })?;
+    }
 
-    cache.load(&cache_path)?;
-
-    // Ensure the index file is up to date
-    patcher
-        .ensure_meta(&mut cache, &versions, &patcher.config.indexfile)
-        .await?;
-
-    // Load the index file
-    let index = patcher.load_manifest(&patcher.config.indexfile).await?;
-
-    // Load the manifests
-    // Need to download both files, so that the default/trunk manifest is there for the on-demand variant
-    patcher
-        .ensure_meta(&mut cache, &index, &patcher.config.defaultmanifestfile)
-        .await?;
-    patcher
-        .ensure_meta(&mut cache, &index, &patcher.config.minimalmanifestfile)
-        .await?;
-
-    // Load the pack catalog
-    let has_pki = patcher
-        .ensure_meta(&mut cache, &index, &patcher.config.packcatalog)
-        .await?;
+    if args.print_server_info {
+        let res = print_server_info(&patcher, &mut cache, &versions, server).await;
+        cache.save(&cache_path)?;
+        return res;
+    }
 
-    let pki = if has_pki {
-        let catalog_file = patcher.dirs.download.join(&patcher.config.packcatalog);
-        let file = std::fs::File::open(&catalog_file)
-            .wrap_err_with(|| eyre!("Failed to open {}", catalog_file.display()))?;
-        PackIndexFile::try_from(file).map_err(|e| eyre!("Failed to load PKI file: {:?}", e))?
-    } else {
-        // PKI file with nothing
-        log::info!("Assuming empty PK catalog");
-        PackIndexFile {
-            archives: vec![],
-            files: Default::default(),
+    let install_file_key = patcher.install_file_key();
+    if args.fetch_installer {
+        let res = patcher
+            .ensure_meta(&mut cache, &versions, &install_file_key)
+            .await;
+        cache.save(&cache_path)?;
+        if res? {
+            let path = patcher.dirs.download.join(&install_file_key);
+            info!("Installer saved to {}", path.display());
         }
-    };
-
-    let variant_menu = menu(vec![
-        button(&format!("Minimal ({})", patcher.config.minimalmanifestfile)),
-        button(&format!("Default ({})", patcher.config.defaultmanifestfile)),
-    ]);
+    }
 
-    run(&variant_menu);
+    if args.installer_info {
+        match versions.files.get(&install_file_key) {
+            Some(f) => {
+                println!("installer: {}", install_file_key);
+                println!("size: {} bytes", f.filesize);
+                println!("md5: {}", f.hash);
+                println!("compressed size: {} bytes", f.compressed_filesize);
+                println!("compressed md5: {}", f.compressed_hash);
+                println!("url: {}", patcher.get_url(f)?);
+            }
+            None => println!("installer: {} not found", install_file_key),
+        }
+        return Ok(());
+    }
 
-    let sel = mut_menu(&variant_menu).selected_item_index();
-    let manifestfile = if sel == 0 {
-        &patcher.config.minimalmanifestfile
-    } else {
-        &patcher.config.defaultmanifestfile
+    let variant = match &registered {
+        Some((_, install)) => install.variant,
+        None => select_variant(&patcher.config),
+    };
+    if let Some(mirror) = rollback.as_ref().and_then(|pin| pin.mirror.as_deref()) {
+        info!("Downloading from {}", mirror);
+        // Keep the last path segment when joining the file paths
+        patcher.url = Url::parse(&format!("{}/", mirror.trim_end_matches('/')))?;
     }
-    .as_str();
+    let manifestfile = patcher.config.manifest_file(variant);
 
     info!("Using manifest {}", manifestfile);
 
-    let manifest = patcher.load_manifest(manifestfile).await?;
+    // Remove leftovers of an earlier run that was killed
+    patcher.remove_partial(manifestfile).await?;
 
-    for key in manifest.files.keys() {
-        patcher
-            .ensure_file(&mut cache, &pki, &manifest, key)
-            .await?;
+    let cancel = cancel::install();
+    // Only take commands from a terminal, or from a script that set a limit
+    if args.limit_rate.is_some() || atty::is(atty::Stream::Stdin) {
+        ratelimit::control_from_stdin(patcher.net.rate_limit().clone());
     }
+    let res = match &rollback {
+        Some(pin) => {
+            patcher
+                .rollback(&mut cache, pin.version, manifestfile, &cancel)
+                .await
+        }
+        None => {
+            patcher
+                .update(&mut cache, &versions, manifestfile, &cancel)
+                .await
+        }
+    };
 
     cache.save(&cache_path)?;
 
-    // Create boot.cfg
+    let pki = match res {
+        Ok(pki) => pki,
+        Err(e) if e.is::<Cancelled>() => {
+            let count = patcher.remove_partial(manifestfile).await?;
+            warn!("Patch cancelled, removed {} temporary file(s)", count);
+            std::process::exit(EXIT_CANCELLED);
+        }
+        Err(e) => return Err(e),
+    };
 
-    let token = boot::Token {
-        install_path: patcher.dirs.install.to_string_lossy(),
+    let version = match rollback {
+        Some(pin) => {
+            patcher.pin(&pin)?;
+            pin.version
+        }
+        None => {
+            let version = versions.version.version;
+            if let Err(e) = patcher.snapshot(version) {
+                warn!("Failed to save the manifests of version {}: {}", version, e);
+            }
+            version
+        }
     };
-    let canonical_config_file = patcher.config.configfile.replace('\\', "/");
-    let configfile = token.resolve(&canonical_config_file);
 
-    info!("Config file: {:?}", configfile);
-    let patch_server_port = if server.cdn_info.secure { 443 } else { 80 };
-    let config = boot::BootConfig {
-        server_name: server.name.clone(),
-        patch_server_ip: server.cdn_info.patcher_url.clone(),
-        patch_server_port,
-        auth_server_ip: server.authentication_ip.clone(),
-        logging: server.log_level as i32,
-        data_center_id: server.data_center_id,
-        cp_code: server.cdn_info.cp_code as i32,
-        akamai_dlm: server.cdn_info.use_dlm,
-        patch_server_dir: server.cdn_info.patcher_dir.clone(),
-        ugc_use_3d_services: server.use3d_services,
-        ugc_server_ip: server.ugc_cdn_info.patcher_url.clone(),
-        ugc_server_dir: server.ugc_cdn_info.patcher_dir.clone(),
-        manifest_file: patcher.config.defaultmanifestfile,
-        passurl: env_info.account_info.send_password_url,
-        sign_in_url: env_info.account_info.sign_in_url,
-        sign_up_url: env_info.account_info.sign_up_url,
-        register_url: env_info.game_info.client_url,
-        crash_log_url: env_info.game_info.crash_log_url,
-        locale: server.language.clone(),
-        track_disk_usage: true,
-        use_catalog: !pki.archives.is_empty(),
-    };
-    let config_path = Path::new(configfile.as_ref());
-    let config_text = config.to_cfg()?;
-
-    tokio::fs::write(config_path, config_text)
-        .await
-        .wrap_err_with(|| eyre!("Failed to write {}", config_path.display()))?;
+    // Create boot.cfg
+    let tokens = patcher.tokens(&server.language, &args.var);
+    let config = BootConfig::new(
+        server,
+        &env_info,
+        &patcher.config,
+        variant,
+        &pki,
+        &args.boot_set,
+    )?;
+    let config_path = match args.boot_format {
+        Format::Cfg => Some(boot_cfg_path(
+            &patcher.config.configfile,
+            &tokens,
+            &patcher.dirs.drives,
+        )?),
+        _ => None,
+    };
+    write_boot_cfg(&config, args.boot_format, config_path.as_deref()).await?;
+
+    if let Some((name, _)) = &registered {
+        let path = registry_path()?;
+        let mut registry = Registry::load(&path)?;
+        if let Some(install) = registry.get_mut(name) {
+            install.version = Some(version);
+            install.cache_file = Some(cache_path);
+            registry.save(&path)?;
+        }
+    }
 
     Ok(())
 }
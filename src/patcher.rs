@@ -1,30 +1,75 @@
 use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    io::ErrorKind,
     path::{Path, PathBuf},
     time::SystemTime,
 };
 
 use assembly_pack::{
+    md5::MD5Sum,
     pki::core::PackIndexFile,
     txt::{FileLine, Manifest},
 };
 use assembly_xml::universe_config::CdnInfo;
 use color_eyre::eyre::{eyre, WrapErr};
-use log::info;
+use log::{info, warn};
 use reqwest::Url;
+use serde::{Deserialize, Serialize};
 use tokio::io::BufReader;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
+    boot::Tokens,
     cache::{Cache, CacheEntry, CacheKey},
+    cancel::or_cancel,
     config::PatcherConfig,
     crc::calculate_crc,
-    download::Downloader,
+    download::{self, Downloader},
     manifest::load_manifest,
-    util::join,
+    space,
+    util::{config_dir, hash_file, into_io_error, join, link_or_copy},
+    winpath::{DriveMap, WinPath},
 };
 
+/// The name of the local override file in the install directory
+pub const OVERRIDE_FILE: &str = "lux-patcher.ini";
+
+/// The name of the versioned `patcher.ini` in a snapshot
+const SNAPSHOT_CONFIG: &str = "patcher.ini";
+
+/// The version that [`Patcher::rollback`] keeps an install at
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pin {
+    pub version: u32,
+    /// The base URL of a mirror that has the files of that version
+    pub mirror: Option<String>,
+}
+
 pub struct PatcherBuilder {
     pub url: Url,
     pub config: PatcherConfig,
+    /// The local override files that were applied, in order
+    pub override_files: Vec<PathBuf>,
+    /// Where the Windows drives and shares from the config are on this host
+    pub drives: DriveMap,
+}
+
+/// Apply the local override file at `path` to `config`, if it exists
+///
+/// Returns whether the file exists.
+fn apply_override(config: &mut PatcherConfig, path: &Path) -> color_eyre::Result<bool> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).wrap_err_with(|| eyre!("Failed to read {}", path.display())),
+    };
+    let local: PatcherConfig = text
+        .parse()
+        .wrap_err_with(|| eyre!("Failed to parse {}", path.display()))?;
+    info!("Applying overrides from {}", path.display());
+    config.merge(&local, path);
+    Ok(true)
 }
 
 impl PatcherBuilder {
@@ -47,18 +92,97 @@ impl PatcherBuilder {
 
         info!("Downloaded patcher config");
 
-        Ok(PatcherBuilder { url, config })
+        Ok(PatcherBuilder {
+            url,
+            config,
+            override_files: Vec::new(),
+            drives: DriveMap::default(),
+        })
     }
 
-    pub fn build(self, net: Downloader, install_dir: Option<&Path>) -> color_eyre::Result<Patcher> {
-        let dirs = PatcherDirs::new(&self.config, install_dir)?;
-        let keys = PatcherKeys {
-            download: format!("{}/", &self.config.downloaddirectory),
-            install: String::new(),
-        };
+    /// Resolve the Windows paths from the config with `drives`
+    pub fn with_drives(mut self, drives: DriveMap) -> Self {
+        self.drives = drives;
+        self
+    }
+
+    fn apply_override(&mut self, path: PathBuf) -> color_eyre::Result<()> {
+        if apply_override(&mut self.config, &path)? {
+            self.override_files.push(path);
+        }
+        Ok(())
+    }
+
+    /// Apply the local override files and find the directories
+    ///
+    /// The override file in the user config directory is applied first and may
+    /// change the install path. The one in the install directory is applied after
+    /// that, so it can't.
+    pub fn resolve(&mut self, install_dir: Option<&Path>) -> color_eyre::Result<PatcherDirs> {
+        if let Some(dir) = config_dir() {
+            self.apply_override(dir.join("patcher.ini"))?;
+        }
+        let dirs = PatcherDirs::resolve(&self.config, install_dir, &self.drives)?;
+        self.apply_override(dirs.install.join(OVERRIDE_FILE))?;
+        let dirs = PatcherDirs::resolve(&self.config, Some(&dirs.install), &self.drives)?;
+        Ok(dirs)
+    }
+
+    /// Download the manifest `name` to `dir`, without an install
+    ///
+    /// `name` may be the version file, the index or any file listed in the index.
+    pub async fn fetch_manifest(
+        &self,
+        net: &Downloader,
+        name: &str,
+        dir: &Path,
+    ) -> color_eyre::Result<Manifest> {
+        let version_url = self.url.join(&self.config.versionfile)?;
+        let versions = load_manifest(net.get_bytes_tokio(version_url).await?).await?;
+        if name == self.config.versionfile {
+            return Ok(versions);
+        }
+        let indexfile = &self.config.indexfile;
+        let index = self.fetch_listed(net, &versions, indexfile, dir).await?;
+        if name == indexfile {
+            return Ok(index);
+        }
+        self.fetch_listed(net, &index, name, dir).await
+    }
+
+    /// Download the manifest `name` listed in `manifest` to `dir`
+    async fn fetch_listed(
+        &self,
+        net: &Downloader,
+        manifest: &Manifest,
+        name: &str,
+        dir: &Path,
+    ) -> color_eyre::Result<Manifest> {
+        let f = manifest
+            .files
+            .get(name)
+            .ok_or_else(|| eyre!("{} is not listed in {}", name, manifest.version.name))?;
+        let url = self.url.join(&f.to_path())?;
+        let path = dir.join(name);
+        net.download(url, f, &path).await?;
+        let file = tokio::fs::File::open(&path).await?;
+        load_manifest(BufReader::new(file)).await
+    }
+
+    pub fn build(
+        mut self,
+        net: Downloader,
+        install_dir: Option<&Path>,
+    ) -> color_eyre::Result<Patcher> {
+        let dirs = self.resolve(install_dir)?;
+        dirs.create()?;
+        let keys = PatcherKeys::new(&self.config);
         Ok(Patcher {
+            check_space: true,
             url: self.url,
             config: self.config,
+            override_files: self.override_files,
+            drives: self.drives,
             net,
             dirs,
             keys,
@@ -69,24 +193,51 @@ impl PatcherBuilder {
 pub struct PatcherDirs {
     pub install: PathBuf,
     pub download: PathBuf,
+    /// `drives`, and the `defaultinstallpath` if the install dir was given
+    pub drives: DriveMap,
 }
 
 impl PatcherDirs {
-    pub fn new(cfg: &PatcherConfig, install_dir: Option<&Path>) -> std::io::Result<Self> {
+    /// Find the directories, relative to the current directory
+    ///
+    /// With an explicit `install_dir`, other paths below the
+    /// `defaultinstallpath` resolve to it as well.
+    pub fn resolve(
+        cfg: &PatcherConfig,
+        install_dir: Option<&Path>,
+        drives: &DriveMap,
+    ) -> std::io::Result<Self> {
+        let mut drives = drives.clone();
         let install = {
             let mut dir = std::env::current_dir()?;
-            let install_path = install_dir.unwrap_or_else(|| Path::new(&cfg.defaultinstallpath));
-            join(&mut dir, install_path);
+            let install_path = match install_dir {
+                Some(path) => path.to_owned(),
+                None => WinPath::parse(&cfg.defaultinstallpath)
+                    .to_native(&drives)
+                    .map_err(into_io_error)?,
+            };
+            join(&mut dir, &install_path);
             dir
         };
-        info!("Install dir: {}", install.display());
-        std::fs::create_dir_all(&install)?;
-
+        if install_dir.is_some() {
+            // Not an error, the default may be a relative path
+            let _ = drives.insert(&cfg.defaultinstallpath, install.clone());
+        }
         let download = install.join(&cfg.downloaddirectory);
-        info!("Download dir: {}", download.display());
-        std::fs::create_dir_all(&download)?;
+        Ok(Self {
+            install,
+            download,
+            drives,
+        })
+    }
 
-        Ok(Self { install, download })
+    /// Create the directories, if they don't exist yet
+    pub fn create(&self) -> std::io::Result<()> {
+        info!("Install dir: {}", self.install.display());
+        std::fs::create_dir_all(&self.install)?;
+        info!("Download dir: {}", self.download.display());
+        std::fs::create_dir_all(&self.download)?;
+        Ok(())
     }
 }
 
@@ -95,15 +246,141 @@ pub struct PatcherKeys {
     install: String,
 }
 
+impl PatcherKeys {
+    fn new(cfg: &PatcherConfig) -> Self {
+        Self {
+            download: format!("{}/", &cfg.downloaddirectory),
+            install: String::new(),
+        }
+    }
+}
+
+/// Whether the file for `key` is missing or outdated
+fn needs_download(cache: &Cache, key: &CacheKey, f: &FileLine) -> bool {
+    cache.get(key).map_or(true, |c| c.hash != f.hash)
+}
+
+/// The downloads of an update, to check the disk space beforehand
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// The number of files to download
+    pub files: usize,
+    /// The uncompressed size of these files
+    ///
+    /// The sd0 data is decoded while streaming, so each download only needs
+    /// its `.part` file next to the old version, which is this size. Replaced
+    /// files free their old space, so this is an upper bound.
+    pub bytes: u64,
+}
+
+impl Plan {
+    /// Find the files in `keys` below `base_key` that need to be downloaded
+    ///
+    /// Files in `pki` are skipped, just like when updating.
+    fn new<'a>(
+        cache: &Cache,
+        manifest: &Manifest,
+        base_key: &str,
+        keys: impl IntoIterator<Item = &'a str>,
+        pki: Option<&PackIndexFile>,
+    ) -> Self {
+        let mut plan = Self::default();
+        for key in keys {
+            let in_pki = pki.map_or(false, |pki| {
+                pki.files.contains_key(&calculate_crc(key.as_bytes()))
+            });
+            let f = match manifest.files.get(key) {
+                Some(f) if !in_pki => f,
+                _ => continue,
+            };
+            if needs_download(cache, &CacheKey::new(&(base_key.to_owned() + key)), f) {
+                plan.files += 1;
+                plan.bytes += u64::from(f.filesize);
+            }
+        }
+        plan
+    }
+}
+
+/// The files of the current update by hash, to materialize duplicates locally
+#[derive(Default)]
+pub struct Dedup {
+    paths: HashMap<[u8; 16], PathBuf>,
+    /// The number of files that were linked or copied instead of downloaded
+    pub files: usize,
+    pub bytes_saved: u64,
+}
+
+impl Dedup {
+    /// Link or copy a file of this update with the contents of `f` to `path`
+    ///
+    /// Returns `false` if there is no such file yet, so that `f` needs to be
+    /// downloaded. Otherwise, the download is counted as saved.
+    fn place(&mut self, f: &FileLine, path: &Path) -> std::io::Result<bool> {
+        let source = match self.paths.get(&f.hash.0) {
+            Some(source) => source,
+            None => return Ok(false),
+        };
+        info!("{} is a duplicate of {}", path.display(), source.display());
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        link_or_copy(source, path)?;
+        self.files += 1;
+        self.bytes_saved += u64::from(f.compressed_filesize);
+        Ok(true)
+    }
+
+    /// Remember that `path` has the contents of `f`
+    fn insert(&mut self, f: &FileLine, path: PathBuf) {
+        self.paths.entry(f.hash.0).or_insert(path);
+    }
+}
+
 pub struct Patcher {
+    /// Whether to check for free disk space before downloading
+    pub check_space: bool,
     pub url: Url,
     pub config: PatcherConfig,
+    pub override_files: Vec<PathBuf>,
+    pub drives: DriveMap,
     pub net: Downloader,
     pub dirs: PatcherDirs,
     pub keys: PatcherKeys,
 }
 
 impl Patcher {
+    /// Switch to the versioned `patcher.ini` at `path`
+    ///
+    /// The local overrides are applied on top of it again, and all settings
+    /// that differ from the current config are logged.
+    pub fn reload_config(&mut self, path: &Path) -> color_eyre::Result<()> {
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| eyre!("Failed to read {}", path.display()))?;
+        let mut config: PatcherConfig = text
+            .parse()
+            .wrap_err_with(|| eyre!("Failed to parse {}", path.display()))?;
+        for file in &self.override_files {
+            apply_override(&mut config, file)?;
+        }
+
+        let changes = self.config.diff(&config);
+        if changes.is_empty() {
+            info!("Versioned patcher config matches the bootstrap config");
+            return Ok(());
+        }
+        for (key, old, new) in &changes {
+            info!(
+                "patcher config: {} changed from {:?} to {:?}",
+                key, old, new
+            );
+        }
+
+        self.dirs = PatcherDirs::resolve(&config, Some(&self.dirs.install), &self.drives)?;
+        self.dirs.create()?;
+        self.keys = PatcherKeys::new(&config);
+        self.config = config;
+        Ok(())
+    }
+
     pub async fn load_manifest(&self, manifestfile: &str) -> color_eyre::Result<Manifest> {
         let path = self.dirs.download.join(manifestfile);
         let file = tokio::fs::File::open(&path).await?;
@@ -112,6 +389,284 @@ impl Patcher {
         Ok(manifest)
     }
 
+    /// Remove the temporary files that an interrupted patch left behind
+    ///
+    /// These are the ones next to the files of `manifestfile`, if it was
+    /// downloaded, and the ones in the download directory. Returns the number
+    /// of files that were removed.
+    pub async fn remove_partial(&self, manifestfile: &str) -> color_eyre::Result<usize> {
+        let mut files = Vec::new();
+        if self.dirs.download.join(manifestfile).is_file() {
+            let manifest = self.load_manifest(manifestfile).await?;
+            let install = &self.dirs.install;
+            files.extend(manifest.files.keys().map(|key| install.join(key)));
+        }
+        Ok(download::remove_partial(&self.dirs.download, files)?)
+    }
+
+    /// Update the meta files and then all files in `manifestfile`
+    ///
+    /// Returns the pack catalog. When `cancel` is triggered, the file that is
+    /// currently being downloaded is abandoned and this returns [`crate::cancel::Cancelled`].
+    pub async fn update(
+        &self,
+        cache: &mut Cache,
+        versions: &Manifest,
+        manifestfile: &str,
+        cancel: &CancellationToken,
+    ) -> color_eyre::Result<PackIndexFile> {
+        // Ensure the index file is up to date
+        or_cancel(
+            cancel,
+            self.ensure_meta(cache, versions, &self.config.indexfile),
+        )
+        .await??;
+
+        // Load the index file
+        let index = self.load_manifest(&self.config.indexfile).await?;
+
+        // Load the manifests
+        // Need to download both files, so that the default/trunk manifest is there for the on-demand variant
+        let defaultmanifestfile = &self.config.defaultmanifestfile;
+        let minimalmanifestfile = &self.config.minimalmanifestfile;
+        let packcatalog = &self.config.packcatalog;
+        let meta: [&str; 3] = [defaultmanifestfile, minimalmanifestfile, packcatalog];
+        let meta_plan = Plan::new(cache, &index, &self.keys.download, meta, None);
+
+        or_cancel(cancel, self.ensure_meta(cache, &index, defaultmanifestfile)).await??;
+        or_cancel(cancel, self.ensure_meta(cache, &index, minimalmanifestfile)).await??;
+
+        // Load the pack catalog
+        let has_pki = or_cancel(cancel, self.ensure_meta(cache, &index, packcatalog)).await??;
+
+        let pki = if has_pki {
+            load_pki(&self.dirs.download.join(packcatalog))?
+        } else {
+            empty_pki()
+        };
+
+        self.patch_files(cache, &pki, manifestfile, &meta_plan, cancel)
+            .await?;
+        Ok(pki)
+    }
+
+    /// Update all files in `manifestfile` that are not in the pack catalog
+    ///
+    /// `meta_plan` are the meta files this update downloaded, see
+    /// [`Patcher::check_space`].
+    async fn patch_files(
+        &self,
+        cache: &mut Cache,
+        pki: &PackIndexFile,
+        manifestfile: &str,
+        meta_plan: &Plan,
+        cancel: &CancellationToken,
+    ) -> color_eyre::Result<()> {
+        let manifest = self.load_manifest(manifestfile).await?;
+
+        let keys = manifest.files.keys().map(String::as_str);
+        let plan = Plan::new(cache, &manifest, &self.keys.install, keys, Some(pki));
+        info!("{} file(s) to download, {} bytes", plan.files, plan.bytes);
+        self.check_space(meta_plan, &plan)?;
+
+        let mut dedup = Dedup::default();
+        for key in manifest.files.keys() {
+            let ensure = self.ensure_file(cache, pki, &manifest, key, &mut dedup);
+            or_cancel(cancel, ensure).await??;
+        }
+        if dedup.files > 0 {
+            info!(
+                "Linked or copied {} duplicate file(s), saved {} bytes of downloads",
+                dedup.files, dedup.bytes_saved
+            );
+        }
+        Ok(())
+    }
+
+    /// Make sure there is enough space for the meta files of `meta_plan` in
+    /// the download directory and the files of `plan` in the install directory
+    ///
+    /// Both are checked in one go, so that they add up if the directories are
+    /// on the same filesystem. The files to patch are only known from the meta
+    /// files, so these are already downloaded and counted again, which keeps
+    /// this an upper bound. If the files can't be hardlinked into the blob
+    /// store, the store needs space for a copy of each of them as well.
+    fn check_space(&self, meta_plan: &Plan, plan: &Plan) -> color_eyre::Result<()> {
+        let bytes = meta_plan.bytes + plan.bytes;
+        if !self.check_space || bytes == 0 {
+            return Ok(());
+        }
+        let mut needs = vec![
+            (self.dirs.download.as_path(), meta_plan.bytes),
+            (self.dirs.install.as_path(), plan.bytes),
+        ];
+        if let Some(store) = self.net.store() {
+            if !store.can_link(&self.dirs.install)? {
+                needs.push((store.root(), bytes));
+            }
+        }
+        space::check(&needs)
+    }
+
+    /// The manifests that make up one version of the client
+    fn snapshot_files(&self) -> [&str; 4] {
+        [
+            &self.config.indexfile,
+            &self.config.defaultmanifestfile,
+            &self.config.minimalmanifestfile,
+            &self.config.packcatalog,
+        ]
+    }
+
+    fn snapshot_dir(&self, version: u32) -> PathBuf {
+        self.dirs
+            .download
+            .join("snapshots")
+            .join(version.to_string())
+    }
+
+    /// Keep a copy of the current manifests and `patcher.ini`, to roll back to
+    /// `version` later
+    pub fn snapshot(&self, version: u32) -> color_eyre::Result<()> {
+        let dir = self.snapshot_dir(version);
+        std::fs::create_dir_all(&dir)?;
+        for name in self.snapshot_files() {
+            let path = self.dirs.download.join(name);
+            if path.exists() {
+                link_or_copy(&path, &dir.join(name))?;
+            }
+        }
+        let config = self.dirs.install.join(self.config_key());
+        if config.exists() {
+            link_or_copy(&config, &dir.join(SNAPSHOT_CONFIG))?;
+        }
+        info!(
+            "Saved the manifests of version {} to {}",
+            version,
+            dir.display()
+        );
+        Ok(())
+    }
+
+    /// The versions that have a snapshot, in ascending order
+    pub fn snapshots(&self) -> std::io::Result<Vec<u32>> {
+        let dir = self.dirs.download.join("snapshots");
+        let mut versions = Vec::new();
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(versions),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            if let Some(version) = entry?.file_name().to_str().and_then(|s| s.parse().ok()) {
+                versions.push(version);
+            }
+        }
+        versions.sort_unstable();
+        Ok(versions)
+    }
+
+    /// The marker of the version that [`Patcher::rollback`] pinned the install to
+    fn pin_file(&self) -> PathBuf {
+        self.dirs.download.join("snapshots").join("pin.json")
+    }
+
+    /// The version the install is pinned to, if any
+    pub fn pinned(&self) -> color_eyre::Result<Option<Pin>> {
+        let path = self.pin_file();
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let pin = serde_json::from_str(&text)
+            .wrap_err_with(|| eyre!("Invalid pin in {}", path.display()))?;
+        Ok(Some(pin))
+    }
+
+    /// Keep the install at the version of `pin` instead of updating it
+    pub fn pin(&self, pin: &Pin) -> std::io::Result<()> {
+        let path = self.pin_file();
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let text = serde_json::to_string_pretty(pin).map_err(into_io_error)?;
+        std::fs::write(path, text)
+    }
+
+    /// Let the install update again, returns whether it was pinned
+    pub fn unpin(&self) -> std::io::Result<bool> {
+        match std::fs::remove_file(self.pin_file()) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Switch to the `patcher.ini` that was saved with the snapshot of `version`
+    ///
+    /// Snapshots of older runs don't have one, in which case the current
+    /// config is kept.
+    pub fn restore_config(&mut self, version: u32) -> color_eyre::Result<()> {
+        let snapshot = self.snapshot_dir(version).join(SNAPSHOT_CONFIG);
+        if !snapshot.exists() {
+            warn!(
+                "No patcher config in the snapshot of version {}, keeping the current one",
+                version
+            );
+            return Ok(());
+        }
+        let path = self.dirs.install.join(self.config_key());
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        link_or_copy(&snapshot, &path)?;
+        self.reload_config(&path)
+    }
+
+    /// Restore the manifests of `version` and patch the files of `manifestfile` to it
+    ///
+    /// The files are downloaded from [`Patcher::url`], which can be pointed to
+    /// a mirror that still has the old files, or taken from the blob store.
+    /// Returns the pack catalog of that version.
+    pub async fn rollback(
+        &self,
+        cache: &mut Cache,
+        version: u32,
+        manifestfile: &str,
+        cancel: &CancellationToken,
+    ) -> color_eyre::Result<PackIndexFile> {
+        let dir = self.snapshot_dir(version);
+        if !dir.is_dir() {
+            return Err(eyre!(
+                "No snapshot of version {}, available: {:?}",
+                version,
+                self.snapshots()?
+            ));
+        }
+        info!("Restoring the manifests of version {}", version);
+        for name in self.snapshot_files() {
+            let snapshot = dir.join(name);
+            if !snapshot.exists() {
+                continue;
+            }
+            let path = self.dirs.download.join(name);
+            link_or_copy(&snapshot, &path)?;
+
+            // Record the old hash, so that the next update replaces the file again
+            let (size, hash) = hash_file(&path)?;
+            let size = u32::try_from(size)?;
+            let cache_key = CacheKey::new(&(self.keys.download.clone() + name));
+            cache.record(cache_key, cache_entry(&path, size, hash)?)?;
+        }
+
+        let catalog_file = self.dirs.download.join(&self.config.packcatalog);
+        let pki = if catalog_file.exists() {
+            load_pki(&catalog_file)?
+        } else {
+            empty_pki()
+        };
+        self.patch_files(cache, &pki, manifestfile, &Plan::default(), cancel)
+            .await?;
+        Ok(pki)
+    }
+
     pub async fn ensure_meta(
         &self,
         cache: &mut Cache,
@@ -124,6 +679,7 @@ impl Patcher {
             &self.dirs.download,
             &self.keys.download,
             file,
+            None,
         )
         .await
         .wrap_err_with(|| eyre!("Failed to ensure meta {}", file))
@@ -135,6 +691,7 @@ impl Patcher {
         pki: &PackIndexFile,
         manifest: &Manifest,
         file: &str,
+        dedup: &mut Dedup,
     ) -> color_eyre::Result<bool> {
         let crc = calculate_crc(file.as_bytes());
         if let Some(meta) = pki.files.get(&crc) {
@@ -150,6 +707,7 @@ impl Patcher {
                 &self.dirs.install,
                 &self.keys.install,
                 file,
+                Some(dedup),
             )
             .await
             .wrap_err_with(|| eyre!("Failed to ensure {}", file))
@@ -163,6 +721,7 @@ impl Patcher {
         base_dir: &Path,
         base_key: &str,
         file: &str,
+        mut dedup: Option<&mut Dedup>,
     ) -> color_eyre::Result<bool> {
         let path = base_dir.join(file);
         if let Some(f) = manifest.files.get(file) {
@@ -173,35 +732,23 @@ impl Patcher {
             let cache_key = base_key.to_owned() + file;
             let cache_key = CacheKey::new(&cache_key);
 
-            // Check whether the file needs to be downloaded
-            let mut needs_download = true;
-            if let Some(c) = cache.get(&cache_key) {
-                if c.hash == f.hash {
-                    needs_download = false;
+            // Download the file, unless this update already placed a copy elsewhere
+            if needs_download(cache, &cache_key, f) {
+                let placed = match dedup.as_deref_mut() {
+                    Some(dedup) => dedup.place(f, &path)?,
+                    None => false,
+                };
+                if !placed {
+                    self.net.download(url, f, &path).await?;
                 }
+                cache.record(cache_key, cache_entry(&path, f.filesize, f.hash)?)?;
             }
-
-            // Download the file
-            if needs_download {
-                self.net.download(url, &self.dirs.download, &path).await?;
-                let meta = tokio::fs::metadata(&path).await?;
-
-                cache.insert(
-                    cache_key,
-                    CacheEntry {
-                        mtime: Some({
-                            let time = meta.modified()?;
-                            let dur = time.duration_since(SystemTime::UNIX_EPOCH)?;
-                            dur.as_secs_f64()
-                        }),
-                        size: f.filesize,
-                        hash: f.hash,
-                    },
-                );
+            if let Some(dedup) = dedup {
+                dedup.insert(f, path);
             }
             Ok(true)
         } else {
-            log::warn!("{} not found in manifest!", file);
+            warn!("{} not found in manifest!", file);
             Ok(false)
         }
     }
@@ -212,6 +759,11 @@ impl Patcher {
         Ok(url)
     }
 
+    /// The values for placeholders in the config, with the user defined `vars`
+    pub fn tokens(&self, locale: &str, vars: &[(String, String)]) -> Tokens {
+        tokens(&self.config, &self.dirs, locale, vars)
+    }
+
     pub fn config_key(&self) -> String {
         format!("{}/patcher.ini", self.config.patcherdirectory)
     }
@@ -223,3 +775,228 @@ impl Patcher {
         )
     }
 }
+
+/// The cache entry for the file at `path`, with its current mtime
+fn cache_entry(path: &Path, size: u32, hash: MD5Sum) -> color_eyre::Result<CacheEntry> {
+    let time = std::fs::metadata(path)?.modified()?;
+    let dur = time.duration_since(SystemTime::UNIX_EPOCH)?;
+    Ok(CacheEntry {
+        mtime: Some(dur.as_secs_f64()),
+        size,
+        hash,
+    })
+}
+
+/// The values for the placeholders in `config`
+pub fn tokens(
+    config: &PatcherConfig,
+    dirs: &PatcherDirs,
+    locale: &str,
+    vars: &[(String, String)],
+) -> Tokens {
+    let patcher_dir = dirs.install.join(&config.patcherdirectory);
+    let mut tokens = Tokens::new();
+    tokens.set("installpath", dirs.install.to_string_lossy());
+    tokens.set("downloaddir", dirs.download.to_string_lossy());
+    tokens.set("patcherdir", patcher_dir.to_string_lossy());
+    tokens.set("locale", locale);
+    tokens.set_from_env();
+    for (name, value) in vars {
+        tokens.set(name, value.as_str());
+    }
+    tokens
+}
+
+/// Load the pack catalog at `path`
+pub fn load_pki(path: &Path) -> color_eyre::Result<PackIndexFile> {
+    let file =
+        std::fs::File::open(path).wrap_err_with(|| eyre!("Failed to open {}", path.display()))?;
+    PackIndexFile::try_from(file).map_err(|e| eyre!("Failed to load PKI file: {:?}", e))
+}
+
+/// A pack catalog without any packs
+pub fn empty_pki() -> PackIndexFile {
+    info!("Assuming empty PK catalog");
+    PackIndexFile {
+        archives: vec![],
+        files: Default::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dedup, Patcher, PatcherBuilder, PatcherDirs, Pin};
+    use crate::{
+        cache::{Cache, CacheEntry, CacheKey},
+        config::PatcherConfig,
+        download::Downloader,
+        winpath::{DriveMap, WinPath},
+    };
+    use assembly_pack::{md5::MD5Sum, txt::FileLine};
+    use std::path::{Path, PathBuf};
+    use tokio_util::sync::CancellationToken;
+
+    /// The manifest line for `data`, compressed to `compressed_filesize` bytes
+    fn file_line(data: &[u8], compressed_filesize: u32) -> FileLine {
+        let hash = MD5Sum(md5::compute(data).0);
+        FileLine {
+            filesize: data.len() as u32,
+            hash,
+            compressed_filesize,
+            compressed_hash: MD5Sum([0; 16]),
+            line_hash: hash,
+        }
+    }
+
+    #[test]
+    fn test_dedup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let same = file_line(b"same", 30);
+        let other = file_line(b"other", 40);
+        let files = [
+            ("client/a.dll", b"same".as_slice(), &same),
+            ("client/res/b.dll", b"same".as_slice(), &same),
+            ("client/c.dll", b"other".as_slice(), &other),
+            ("client/res/d/e.dll", b"same".as_slice(), &same),
+        ];
+
+        // Simulate the downloads of `Patcher::ensure`
+        let mut dedup = Dedup::default();
+        let mut downloads = Vec::new();
+        for (name, data, f) in files {
+            let path = tmp.path().join(name);
+            if !dedup.place(f, &path).unwrap() {
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, data).unwrap();
+                downloads.push(name);
+            }
+            dedup.insert(f, path);
+        }
+
+        assert_eq!(downloads, ["client/a.dll", "client/c.dll"]);
+        assert_eq!((dedup.files, dedup.bytes_saved), (2, 60));
+        for (name, data, _) in files {
+            assert_eq!(std::fs::read(tmp.path().join(name)).unwrap(), data);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let inode = |name| std::fs::metadata(tmp.path().join(name)).unwrap().ino();
+            assert_eq!(inode("client/a.dll"), inode("client/res/d/e.dll"));
+        }
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_dirs_map_install_path() {
+        let config: PatcherConfig = "[patcher]\r\ndefaultinstallpath=C:\\Games\\LU\r\n"
+            .parse()
+            .unwrap();
+        let drives = DriveMap::default();
+        assert!(PatcherDirs::resolve(&config, None, &drives).is_err());
+
+        let dirs = PatcherDirs::resolve(&config, Some(Path::new("/srv/lu")), &drives).unwrap();
+        let boot_cfg = WinPath::parse("C:\\Games\\LU\\client\\boot.cfg");
+        let native = boot_cfg.to_native(&dirs.drives).unwrap();
+        assert_eq!(native, PathBuf::from("/srv/lu/client/boot.cfg"));
+        assert!(WinPath::parse("C:\\Windows")
+            .to_native(&dirs.drives)
+            .is_err());
+    }
+
+    /// A patcher with the default config that installs to `dir`
+    fn test_patcher(dir: &Path) -> Patcher {
+        let builder = PatcherBuilder {
+            url: "http://localhost/patcher/".parse().unwrap(),
+            config: "[patcher]\r\n".parse().unwrap(),
+            override_files: Vec::new(),
+            drives: DriveMap::default(),
+        };
+        builder.build(Downloader::new(), Some(dir)).unwrap()
+    }
+
+    #[test]
+    fn test_pin() {
+        let tmp = tempfile::tempdir().unwrap();
+        let patcher = test_patcher(tmp.path());
+        assert_eq!(patcher.pinned().unwrap(), None);
+        assert!(!patcher.unpin().unwrap());
+
+        let pin = Pin {
+            version: 42,
+            mirror: Some(String::from("http://mirror/luclient")),
+        };
+        patcher.pin(&pin).unwrap();
+        assert_eq!(patcher.pinned().unwrap(), Some(pin));
+        // The marker is not mistaken for a snapshot
+        assert_eq!(patcher.snapshots().unwrap(), Vec::<u32>::new());
+
+        assert!(patcher.unpin().unwrap());
+        assert_eq!(patcher.pinned().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_rollback() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut patcher = test_patcher(tmp.path());
+        let manifestfile = patcher.config.defaultmanifestfile.clone();
+        let manifest_path = patcher.dirs.download.join(&manifestfile);
+        let config_path = patcher.dirs.install.join(patcher.config_key());
+        std::fs::create_dir_all(config_path.parent().unwrap()).unwrap();
+
+        // Version 1 is installed, and its file is in the cache
+        let data = b"Hello World!\n";
+        let f = file_line(data, 30);
+        let old = format!(
+            "[version]\n1,{0},old\n[files]\nclient/a.txt,{1},{0},{1},{0},{0}\n",
+            f.hash,
+            data.len()
+        );
+        std::fs::create_dir_all(patcher.dirs.install.join("client")).unwrap();
+        std::fs::write(patcher.dirs.install.join("client/a.txt"), data).unwrap();
+        std::fs::write(&manifest_path, &old).unwrap();
+        std::fs::write(&config_path, "[patcher]\r\npatcherexeversion=1.0\r\n").unwrap();
+        let mut cache = Cache::new();
+        let entry = CacheEntry {
+            mtime: None,
+            size: f.filesize,
+            hash: f.hash,
+        };
+        cache.insert(CacheKey::new("client/a.txt"), entry);
+        patcher.snapshot(1).unwrap();
+
+        // Version 2 replaced the manifest and the config, which are never
+        // changed in place, as the snapshot links to them
+        let new = old.replacen("1,", "2,", 1).replace(",old", ",new");
+        for (path, text) in [
+            (&manifest_path, new.as_str()),
+            (&config_path, "[patcher]\r\npatcherexeversion=2.0\r\n"),
+        ] {
+            std::fs::remove_file(path).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+
+        patcher.restore_config(1).unwrap();
+        assert_eq!(patcher.config.patcherexeversion, "1.0");
+
+        // The file is in the cache, so nothing is downloaded
+        let cancel = CancellationToken::new();
+        let pki = patcher
+            .rollback(&mut cache, 1, &manifestfile, &cancel)
+            .await
+            .unwrap();
+        assert!(pki.archives.is_empty());
+        assert_eq!(std::fs::read_to_string(&manifest_path).unwrap(), old);
+        let key = CacheKey::new(&(patcher.keys.download.clone() + &manifestfile));
+        let restored = cache.get(&key).unwrap();
+        assert_eq!(restored.hash.0, md5::compute(&old).0);
+
+        let err = patcher
+            .rollback(&mut cache, 2, &manifestfile, &cancel)
+            .await;
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .starts_with("No snapshot of version 2"));
+    }
+}
@@ -0,0 +1,218 @@
+//! Limiting the download bandwidth
+
+use std::{
+    io::BufRead,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use tokio::sync::watch;
+
+/// Parse a rate in bytes per second, with an optional `k`, `m` or `g` suffix
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    let lower = value.to_ascii_lowercase();
+    let (number, factor) = match lower.as_bytes().last() {
+        Some(b'k') => (&lower[..lower.len() - 1], 1 << 10),
+        Some(b'm') => (&lower[..lower.len() - 1], 1 << 20),
+        Some(b'g') => (&lower[..lower.len() - 1], 1 << 30),
+        _ => (lower.as_str(), 1),
+    };
+    match number.parse::<u64>() {
+        Ok(rate) if rate > 0 => rate
+            .checked_mul(factor)
+            .ok_or_else(|| format!("rate {:?} is too large", value)),
+        _ => Err(format!("expected a rate like 500k or 2M, got {:?}", value)),
+    }
+}
+
+struct Bucket {
+    /// The bytes that may be passed on right now, negative while in debt
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    /// Add the tokens for the time since the last refill, up to one second worth
+    fn refill(&mut self, rate: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last = now;
+    }
+}
+
+/// A token bucket that is shared by all downloads of a [`crate::download::Downloader`]
+///
+/// The rate can be changed at any time with [`RateLimit::set`], which also
+/// affects the downloads that are currently waiting.
+#[derive(Clone)]
+pub struct RateLimit {
+    bucket: Arc<Mutex<Bucket>>,
+    rate: Arc<watch::Sender<Option<u64>>>,
+}
+
+impl RateLimit {
+    /// A limit of `rate` bytes per second, or no limit
+    pub fn new(rate: Option<u64>) -> Self {
+        let (sender, _) = watch::channel(rate);
+        let bucket = Bucket {
+            tokens: 0.0,
+            last: Instant::now(),
+        };
+        Self {
+            bucket: Arc::new(Mutex::new(bucket)),
+            rate: Arc::new(sender),
+        }
+    }
+
+    /// Change the limit, in bytes per second
+    pub fn set(&self, rate: Option<u64>) {
+        match rate {
+            Some(rate) => info!("Limiting downloads to {} bytes/s", rate),
+            None => info!("Not limiting downloads"),
+        }
+        self.rate.send_replace(rate);
+    }
+
+    /// Wait until `len` more bytes may be passed on
+    pub async fn acquire(&self, len: usize) {
+        let mut rate_rx = self.rate.subscribe();
+        let rate = match *rate_rx.borrow_and_update() {
+            Some(rate) => rate as f64,
+            None => return,
+        };
+        // Take the tokens now and wait for the debt of this and all earlier calls
+        let mut debt = {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.refill(rate);
+            bucket.tokens -= len as f64;
+            -bucket.tokens
+        };
+        let mut rate = rate;
+        while debt > 0.0 {
+            let start = Instant::now();
+            let wait = Duration::from_secs_f64(debt / rate);
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => return,
+                _ = rate_rx.changed() => {
+                    debt -= start.elapsed().as_secs_f64() * rate;
+                    rate = match *rate_rx.borrow_and_update() {
+                        Some(rate) => rate as f64,
+                        None => return,
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Change `limit` with commands on stdin while patching
+///
+/// `limit-rate <rate>` sets a new rate, `limit-rate off` removes the limit.
+pub fn control_from_stdin(limit: RateLimit) {
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("limit-rate"), Some("off")) => limit.set(None),
+                (Some("limit-rate"), Some(rate)) => match parse_rate(rate) {
+                    Ok(rate) => limit.set(Some(rate)),
+                    Err(e) => warn!("{}", e),
+                },
+                (None, _) => {}
+                _ => warn!("Unknown command {:?}, try limit-rate <rate|off>", line),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_rate;
+    use crate::download::Downloader;
+    use std::time::{Duration, Instant};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("1000"), Ok(1000));
+        assert_eq!(parse_rate("500k"), Ok(500 << 10));
+        assert_eq!(parse_rate("2M"), Ok(2 << 20));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert_eq!(
+            parse_rate("99999999999g"),
+            Err(String::from("rate \"99999999999g\" is too large"))
+        );
+    }
+
+    /// Serve `body` to every request on a local port, returning the base URL
+    async fn serve(body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut request = vec![0; 1024];
+                    let _ = socket.read(&mut request).await.unwrap();
+                    let header = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    socket.write_all(header.as_bytes()).await.unwrap();
+                    socket.write_all(&body).await.unwrap();
+                });
+            }
+        });
+        format!("http://{}/", addr)
+    }
+
+    /// Download from `url` with `streams` concurrent requests
+    async fn download_all(net: &Downloader, url: &str, streams: usize) -> (usize, Duration) {
+        let start = Instant::now();
+        let downloads = (0..streams).map(|_| async {
+            let mut reader = net.get_bytes_tokio(url.parse().unwrap()).await.unwrap();
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).await.unwrap();
+            data.len()
+        });
+        let lens = futures_util::future::join_all(downloads).await;
+        (lens.into_iter().sum(), start.elapsed())
+    }
+
+    #[tokio::test]
+    async fn test_limit_rate() {
+        let url = serve(vec![0x55; 50_000]).await;
+        let net = Downloader::new();
+
+        // The limit applies to both streams together
+        net.rate_limit().set(Some(100_000));
+        let (len, elapsed) = download_all(&net, &url, 2).await;
+        assert_eq!(len, 100_000);
+        assert!(elapsed >= Duration::from_millis(800), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+
+        // Lifting the limit wakes up the waiting downloads
+        net.rate_limit().set(Some(1000));
+        let lift = {
+            let limit = net.rate_limit().clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                limit.set(None);
+            }
+        };
+        let ((len, elapsed), ()) = tokio::join!(download_all(&net, &url, 1), lift);
+        assert_eq!(len, 50_000);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+}
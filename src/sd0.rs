@@ -0,0 +1,119 @@
+//! Async decoding of the segmented (`*.sd0`) format
+//!
+//! See [`assembly_pack::sd0`] for the layout. Each chunk is a complete zlib stream,
+//! so chunks are read one at a time and inflated on the blocking thread pool,
+//! which keeps the inflating off the async runtime.
+
+use std::io::Read;
+
+use assembly_pack::{md5::MD5Sum, sd0::MAGIC};
+use color_eyre::eyre::eyre;
+use flate2::read::ZlibDecoder;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Upper bound for the size of a single compressed chunk
+const MAX_CHUNK_SIZE: u32 = 1 << 24;
+
+/// Read the length of the next chunk, or `None` at the end of the stream
+async fn read_chunk_size<R>(reader: &mut R) -> std::io::Result<Option<u32>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut bytes = [0u8; 4];
+    let len = reader.read(&mut bytes).await?;
+    if len == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut bytes[len..]).await?;
+    Ok(Some(u32::from_le_bytes(bytes)))
+}
+
+/// Decompress an sd0 stream from `reader` into `writer`
+///
+/// Fails if the data is larger than `max_size`, which is checked while
+/// inflating, so a chunk can't expand without bound. Returns the size and MD5
+/// hash of the decompressed data.
+pub async fn decode<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    max_size: u64,
+) -> color_eyre::Result<(u64, MD5Sum)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut magic = [0u8; 5];
+    reader.read_exact(&mut magic).await?;
+    if &magic != MAGIC {
+        return Err(eyre!("Magic is wrong: {:?}", magic));
+    }
+
+    let mut context = md5::Context::new();
+    let mut size = 0;
+    while let Some(chunk_size) = read_chunk_size(reader).await? {
+        if chunk_size > MAX_CHUNK_SIZE {
+            return Err(eyre!("Chunk of {} bytes is too large", chunk_size));
+        }
+        let mut chunk = vec![0; chunk_size as usize];
+        reader.read_exact(&mut chunk).await?;
+
+        // Read one byte more than allowed, to tell if the data is too large
+        let limit = max_size - size + 1;
+        let (data, ctx) = tokio::task::spawn_blocking(move || {
+            let mut data = Vec::new();
+            ZlibDecoder::new(&chunk[..])
+                .take(limit)
+                .read_to_end(&mut data)?;
+            context.consume(&data);
+            Ok::<_, std::io::Error>((data, context))
+        })
+        .await??;
+        context = ctx;
+        if data.len() as u64 == limit {
+            return Err(eyre!("Data is larger than {} bytes", max_size));
+        }
+
+        writer.write_all(&data).await?;
+        size += data.len() as u64;
+    }
+    writer.flush().await?;
+
+    Ok((size, MD5Sum(context.compute().0)))
+}
+
+#[cfg(test)]
+mod tests {
+    const BYTES: [u8; 30] = [
+        0x73, 0x64, 0x30, 0x01, 0xff, 0x15, 0x00, 0x00, //
+        0x00, 0x78, 0xda, 0xf3, 0x48, 0xcd, 0xc9, 0xc9, //
+        0x57, 0x08, 0xcf, 0x2f, 0xca, 0x49, 0x51, 0xe4, //
+        0x02, 0x00, 0x20, 0x91, 0x04, 0x48,
+    ];
+
+    #[tokio::test]
+    async fn test_decode() {
+        let mut output = Vec::new();
+        let (size, hash) = super::decode(&mut &BYTES[..], &mut output, 13)
+            .await
+            .unwrap();
+        assert_eq!(output, b"Hello World!\n");
+        assert_eq!(size, 13);
+        assert_eq!(hash.0, md5::compute(b"Hello World!\n").0);
+    }
+
+    #[tokio::test]
+    async fn test_truncated() {
+        let mut output = Vec::new();
+        let res = super::decode(&mut &BYTES[..20], &mut output, 13).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_too_large() {
+        let mut output = Vec::new();
+        let res = super::decode(&mut &BYTES[..], &mut output, 12).await;
+        let err = res.unwrap_err();
+        assert_eq!(err.to_string(), "Data is larger than 12 bytes");
+        assert!(output.is_empty());
+    }
+}
@@ -0,0 +1,108 @@
+//! Checking for free disk space before patching
+
+use std::{fmt, path::Path};
+
+/// Not enough free space for a patch
+#[derive(Debug)]
+pub struct Shortfall {
+    /// A path on the filesystem that is too small
+    pub path: String,
+    pub needed: u64,
+    pub available: u64,
+}
+
+impl fmt::Display for Shortfall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Not enough disk space for {}: {} bytes needed, {} bytes available, {} bytes short",
+            self.path,
+            self.needed,
+            self.available,
+            self.needed - self.available
+        )
+    }
+}
+
+impl std::error::Error for Shortfall {}
+
+/// The space available to this user on the filesystem of `path`
+pub fn available(path: &Path) -> std::io::Result<u64> {
+    fs2::available_space(path)
+}
+
+/// An ID of the filesystem of `path`, if the platform has one
+#[cfg(unix)]
+fn filesystem(path: &Path) -> std::io::Result<Option<u64>> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(Some(std::fs::metadata(path)?.dev()))
+}
+
+/// An ID of the filesystem of `path`, if the platform has one
+#[cfg(not(unix))]
+fn filesystem(_path: &Path) -> std::io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Check that the filesystem of each path has the bytes needed for it free
+///
+/// The needs of paths on the same filesystem are added up. Where the
+/// filesystem can't be told, each path is checked on its own.
+pub fn check(needs: &[(&Path, u64)]) -> color_eyre::Result<()> {
+    let mut totals: Vec<(Option<u64>, &Path, u64)> = Vec::new();
+    for &(path, needed) in needs {
+        let id = filesystem(path)?;
+        match totals
+            .iter_mut()
+            .find(|(other, ..)| id.is_some() && *other == id)
+        {
+            Some((_, _, total)) => *total += needed,
+            None => totals.push((id, path, needed)),
+        }
+    }
+    for (_, path, needed) in totals {
+        let available = available(path)?;
+        if available < needed {
+            return Err(Shortfall {
+                path: path.display().to_string(),
+                needed,
+                available,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+
+    #[test]
+    fn test_check() {
+        let dir = std::env::temp_dir();
+        check(&[(&dir, 0)]).unwrap();
+
+        let err = check(&[(&dir, u64::MAX)]).unwrap_err();
+        let shortfall = err.downcast_ref::<super::Shortfall>().unwrap();
+        assert_eq!(shortfall.needed, u64::MAX);
+        assert!(err.to_string().starts_with("Not enough disk space for "));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_same_filesystem() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (a, b) = (tmp.path().join("a"), tmp.path().join("b"));
+        std::fs::create_dir(&a).unwrap();
+        std::fs::create_dir(&b).unwrap();
+
+        // Each half fits, but not both together
+        let half = super::available(tmp.path()).unwrap() / 2 + 1;
+        check(&[(&a, half)]).unwrap();
+        let err = check(&[(&a, half), (&b, half)]).unwrap_err();
+        let shortfall = err.downcast_ref::<super::Shortfall>().unwrap();
+        assert_eq!(shortfall.needed, 2 * half);
+    }
+}
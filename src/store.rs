@@ -0,0 +1,243 @@
+//! A store of decompressed files that is shared by several installs
+
+use std::{
+    collections::BTreeSet,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use assembly_pack::{md5::MD5Sum, txt::FileLine};
+use log::{info, warn};
+
+use crate::util::{link_or_copy, with_suffix};
+
+/// The list of cache files of the installs that use a store
+const CACHES_FILE: &str = "caches.txt";
+
+/// Decompressed files, keyed by the MD5 hash from the manifest
+///
+/// The blobs use the layout of the patch server (`a/b/<hash>`, see
+/// [`FileLine::to_path`]), without the `.sd0` extension. Files are
+/// hardlinked between the store and the installs where possible, and copied
+/// otherwise, which uses a reflink on filesystems that support it.
+///
+/// The store also lists the cache files of all installs that were patched
+/// with it, so that [`BlobStore::gc`] can keep their files.
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    root: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// The directory of the store
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whether the files in `dir` can be hardlinked into the store
+    ///
+    /// Otherwise, each file added to the store is a copy that takes up space.
+    pub fn can_link(&self, dir: &Path) -> std::io::Result<bool> {
+        std::fs::create_dir_all(&self.root)?;
+        let name = format!(".link-probe-{}.part", std::process::id());
+        let (probe, link) = (dir.join(&name), self.root.join(&name));
+        std::fs::write(&probe, b"")?;
+        let linked = std::fs::hard_link(&probe, &link).is_ok();
+        if linked {
+            std::fs::remove_file(&link)?;
+        }
+        std::fs::remove_file(&probe)?;
+        Ok(linked)
+    }
+
+    /// The path of the blob with `hash`
+    fn blob_path(&self, hash: &MD5Sum) -> PathBuf {
+        let hash = format!("{:?}", hash);
+        self.root.join(&hash[..1]).join(&hash[1..2]).join(hash)
+    }
+
+    /// Place the blob for `f` at `path`, if the store has a copy
+    ///
+    /// Returns whether the blob was found. Blobs are verified when they are
+    /// added and trusted after that, like the files in a cache, so this only
+    /// reads the metadata. A blob of the wrong size, e.g. because a hardlinked
+    /// install was truncated, is removed.
+    pub fn fetch(&self, f: &FileLine, path: &Path) -> std::io::Result<bool> {
+        let blob = self.blob_path(&f.hash);
+        let size = match std::fs::metadata(&blob) {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if size != u64::from(f.filesize) {
+            warn!("Removing damaged blob {}", blob.display());
+            std::fs::remove_file(&blob)?;
+            return Ok(false);
+        }
+        info!("Using {} from the blob store", f.hash);
+        link_or_copy(&blob, path)?;
+        Ok(true)
+    }
+
+    /// Add the file for `f` at `path` to the store
+    ///
+    /// The file must already match the size and hash of `f`.
+    pub fn insert(&self, f: &FileLine, path: &Path) -> std::io::Result<()> {
+        let blob = self.blob_path(&f.hash);
+        if blob.exists() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(blob.parent().unwrap())?;
+        link_or_copy(path, &blob)
+    }
+
+    /// Record that the install with the cache file at `path` uses this store
+    pub fn add_cache(&self, path: &Path) -> std::io::Result<()> {
+        let path = std::env::current_dir()?.join(path);
+        let mut caches = self.read_caches()?;
+        if !caches.contains(&path) {
+            caches.push(path);
+            self.write_caches(&caches)?;
+        }
+        Ok(())
+    }
+
+    /// The cache files of the installs that use this store
+    ///
+    /// Cache files that no longer exist are removed from the list.
+    pub fn caches(&self) -> std::io::Result<Vec<PathBuf>> {
+        let caches = self.read_caches()?;
+        let (existing, missing): (Vec<_>, Vec<_>) = caches.into_iter().partition(|p| p.exists());
+        if !missing.is_empty() {
+            for path in &missing {
+                info!("Forgetting {}, which no longer exists", path.display());
+            }
+            self.write_caches(&existing)?;
+        }
+        Ok(existing)
+    }
+
+    fn read_caches(&self) -> std::io::Result<Vec<PathBuf>> {
+        match std::fs::read_to_string(self.root.join(CACHES_FILE)) {
+            Ok(text) => Ok(text.lines().map(PathBuf::from).collect()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_caches(&self, caches: &[PathBuf]) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.root)?;
+        let mut text = String::new();
+        for path in caches {
+            text.push_str(&path.to_string_lossy());
+            text.push('\n');
+        }
+        let path = self.root.join(CACHES_FILE);
+        let tmp_path = with_suffix(&path, ".tmp");
+        std::fs::write(&tmp_path, text)?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    /// Remove all blobs that are not in `keep`
+    ///
+    /// Returns the number of blobs and bytes removed.
+    pub fn gc(&self, keep: impl IntoIterator<Item = MD5Sum>) -> std::io::Result<(usize, u64)> {
+        let keep: BTreeSet<String> = keep.into_iter().map(|h| format!("{:?}", h)).collect();
+        let mut count = 0;
+        let mut bytes = 0;
+        for level1 in read_dirs(&self.root)? {
+            for level2 in read_dirs(&level1)? {
+                for entry in std::fs::read_dir(&level2)? {
+                    let entry = entry?;
+                    let name = entry.file_name();
+                    if keep.contains(name.to_string_lossy().as_ref()) {
+                        continue;
+                    }
+                    bytes += entry.metadata()?.len();
+                    std::fs::remove_file(entry.path())?;
+                    count += 1;
+                }
+            }
+        }
+        Ok((count, bytes))
+    }
+}
+
+/// The subdirectories of `dir`, or none if it doesn't exist
+fn read_dirs(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlobStore;
+    use assembly_pack::{md5::MD5Sum, txt::FileLine};
+
+    #[test]
+    fn test_store() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let store = BlobStore::new(dir.join("blobs"));
+        let install = dir.join("install");
+        std::fs::create_dir_all(&install).unwrap();
+        assert!(store.can_link(&install).unwrap());
+        assert_eq!(std::fs::read_dir(&install).unwrap().count(), 0);
+        assert_eq!(std::fs::read_dir(store.root()).unwrap().count(), 0);
+
+        let data = b"Hello World!\n";
+        let hash = MD5Sum(md5::compute(data).0);
+        let f = FileLine {
+            filesize: data.len() as u32,
+            hash,
+            compressed_filesize: 0,
+            compressed_hash: hash,
+            line_hash: hash,
+        };
+        let a = install.join("a.txt");
+        let b = install.join("b.txt");
+        assert!(!store.fetch(&f, &b).unwrap());
+
+        std::fs::write(&a, data).unwrap();
+        store.insert(&f, &a).unwrap();
+        assert!(store.fetch(&f, &b).unwrap());
+        assert_eq!(std::fs::read(&b).unwrap(), data);
+
+        // A blob of the wrong size is removed
+        let blob = store.blob_path(&hash);
+        std::fs::remove_file(&blob).unwrap();
+        std::fs::write(&blob, &data[1..]).unwrap();
+        assert!(!store.fetch(&f, &b).unwrap());
+        assert!(!blob.exists());
+        store.insert(&f, &a).unwrap();
+
+        let cache = install.join("quickcheck.txt");
+        store.add_cache(&cache).unwrap();
+        store.add_cache(&cache).unwrap();
+        assert!(store.caches().unwrap().is_empty());
+        std::fs::write(&cache, "").unwrap();
+        store.add_cache(&cache).unwrap();
+        assert_eq!(store.caches().unwrap(), [cache]);
+
+        let (count, bytes) = store.gc([hash]).unwrap();
+        assert_eq!((count, bytes), (0, 0));
+        let (count, bytes) = store.gc([]).unwrap();
+        assert_eq!((count, bytes), (1, data.len() as u64));
+        assert!(!store.fetch(&f, &b).unwrap());
+    }
+}
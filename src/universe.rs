@@ -0,0 +1,310 @@
+//! Loading the universe configuration (`EnvironmentInfo`)
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use assembly_xml::{
+    quick::de::Deserializer,
+    universe_config::{CdnInfo, Environment, Server},
+};
+use color_eyre::eyre::{eyre, WrapErr};
+use log::{info, warn};
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::{
+    download::Downloader,
+    util::{cache_dir, print_table},
+};
+
+/// Where the last `EnvironmentInfo` for `env` is stored
+///
+/// Fails if `env` could name a file outside of the cache directory.
+fn cache_path(env: &str) -> color_eyre::Result<Option<PathBuf>> {
+    let is_special = |c| matches!(c, '/' | '\\' | ':' | '\0');
+    if env.is_empty() || env.contains("..") || env.contains(is_special) {
+        return Err(eyre!(
+            "Invalid environment {:?}, it can't contain path separators or `..`",
+            env
+        ));
+    }
+    Ok(cache_dir().map(|dir| dir.join(format!("env-{}.xml", env))))
+}
+
+/// The URL of the `EnvironmentInfo` service for `env`
+fn env_info_url(cfg_url: &str, env: &str) -> color_eyre::Result<Url> {
+    // Cleanup base parameter
+    let options = Url::options();
+    let api = Url::parse(cfg_url)?;
+    let base_url = options.base_url(Some(&api));
+
+    let mut env_info_url = base_url.parse("UniverseConfig.svc/xml/EnvironmentInfo")?;
+    let env_query = format!("environment={}", env);
+    env_info_url.set_query(Some(&env_query));
+    Ok(env_info_url)
+}
+
+/// A problem with an element of an `EnvironmentInfo` document
+#[derive(Debug)]
+pub struct InvalidElement {
+    /// The path to the element, e.g. `Environment/Servers/Server[0]/Name`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for InvalidElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl std::error::Error for InvalidElement {}
+
+/// Check that the values needed for patching are present
+fn validate(env_info: &Environment) -> Result<(), InvalidElement> {
+    let servers = &env_info.servers.servers;
+    if servers.is_empty() {
+        return Err(InvalidElement {
+            path: "Environment/Servers".to_owned(),
+            message: "no Server elements".to_owned(),
+        });
+    }
+    for (i, server) in servers.iter().enumerate() {
+        let required = [
+            ("Name", &server.name),
+            ("CdnInfo/PatcherUrl", &server.cdn_info.patcher_url),
+        ];
+        for (element, value) in required {
+            if value.trim().is_empty() {
+                return Err(InvalidElement {
+                    path: format!("Environment/Servers/Server[{}]/{}", i, element),
+                    message: "must not be empty".to_owned(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse an `EnvironmentInfo` document
+///
+/// Errors name the path of the element that is missing or invalid.
+pub fn parse(xml: &str) -> Result<Environment, InvalidElement> {
+    let mut de = Deserializer::from_reader(xml.as_bytes());
+    let env_info: Environment = serde_path_to_error::deserialize(&mut de).map_err(|e| {
+        let mut path = String::from("Environment");
+        for segment in e.path().iter() {
+            match segment {
+                serde_path_to_error::Segment::Seq { index } => {
+                    path.push_str(&format!("[{}]", index))
+                }
+                segment => path.push_str(&format!("/{}", segment)),
+            }
+        }
+        InvalidElement {
+            path,
+            message: e.into_inner().to_string(),
+        }
+    })?;
+    validate(&env_info)?;
+    Ok(env_info)
+}
+
+/// Find the server called `name`, ignoring case
+pub fn find_server<'a>(env_info: &'a Environment, name: &str) -> color_eyre::Result<&'a Server> {
+    let servers = &env_info.servers.servers;
+    servers
+        .iter()
+        .find(|s| s.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            let names: Vec<&str> = servers.iter().map(|s| s.name.as_str()).collect();
+            eyre!(
+                "Unknown universe {:?}, expected one of: {}",
+                name,
+                names.join(", ")
+            )
+        })
+}
+
+/// Where to load the universe configuration from
+pub enum Source<'a> {
+    /// The `UniverseConfig.svc` service below this URL
+    Service(&'a str),
+    /// A local `EnvironmentInfo` document
+    File(&'a Path),
+    /// The copy from the last successful lookup
+    Cached,
+}
+
+/// Load the universe configuration for `env`
+///
+/// When fetched from the service, a copy is kept in the user cache directory
+/// for [`Source::Cached`].
+pub async fn load(
+    net: &Downloader,
+    source: Source<'_>,
+    env: &str,
+) -> color_eyre::Result<Environment> {
+    info!("Environment: {}", env);
+
+    let local_path = match source {
+        Source::Service(cfg_url) => return fetch(net, cfg_url, env, cache_path(env)?).await,
+        Source::File(path) => path.to_owned(),
+        Source::Cached => {
+            cache_path(env)?.ok_or_else(|| eyre!("No cache directory for this user"))?
+        }
+    };
+    info!("Loading {}", local_path.display());
+    let xml = std::fs::read_to_string(&local_path)
+        .wrap_err_with(|| eyre!("Failed to read {}", local_path.display()))?;
+    let env_info =
+        parse(&xml).wrap_err_with(|| eyre!("Failed to load {}", local_path.display()))?;
+    Ok(env_info)
+}
+
+/// Fetch the universe configuration from the service and cache it
+async fn fetch(
+    net: &Downloader,
+    cfg_url: &str,
+    env: &str,
+    cache_path: Option<PathBuf>,
+) -> color_eyre::Result<Environment> {
+    let env_info_url = env_info_url(cfg_url, env)?;
+    info!("Loading {}", env_info_url);
+
+    // Get the environment info
+    let xml = net.get_text(env_info_url.clone()).await?;
+    let env_info = parse(&xml).wrap_err_with(|| eyre!("Failed to load {}", env_info_url))?;
+
+    if let Some(path) = cache_path {
+        let res = std::fs::create_dir_all(path.parent().unwrap())
+            .and_then(|()| std::fs::write(&path, &xml));
+        if let Err(e) = res {
+            warn!(
+                "Failed to cache universe config at {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+    Ok(env_info)
+}
+
+/// One row of the `universes` listing
+#[derive(Serialize)]
+pub struct UniverseRow<'a> {
+    pub environment: &'a str,
+    pub name: &'a str,
+    pub authentication_ip: &'a str,
+    pub language: &'a str,
+    pub data_center_id: u32,
+    pub online: bool,
+    pub cdn_info: &'a CdnInfo,
+}
+
+impl<'a> UniverseRow<'a> {
+    pub fn new(environment: &'a str, server: &'a Server) -> Self {
+        Self {
+            environment,
+            name: &server.name,
+            authentication_ip: &server.authentication_ip,
+            language: &server.language,
+            data_center_id: server.data_center_id,
+            online: server.online,
+            cdn_info: &server.cdn_info,
+        }
+    }
+}
+
+/// The patch server URL of a CDN, for display
+pub fn cdn_url(cdn_info: &CdnInfo) -> String {
+    let scheme = if cdn_info.secure { "https" } else { "http" };
+    format!(
+        "{}://{}/{}/",
+        scheme, cdn_info.patcher_url, cdn_info.patcher_dir
+    )
+}
+
+/// Print the universes as an aligned table
+pub fn print_universes(rows: &[UniverseRow]) {
+    let header = ["ENV", "NAME", "AUTH IP", "CDN", "LANG", "DC", "ONLINE"];
+    let cells: Vec<[String; 7]> = rows
+        .iter()
+        .map(|r| {
+            [
+                r.environment.to_owned(),
+                r.name.to_owned(),
+                r.authentication_ip.to_owned(),
+                cdn_url(r.cdn_info),
+                r.language.to_owned(),
+                r.data_center_id.to_string(),
+                r.online.to_string(),
+            ]
+        })
+        .collect();
+
+    print_table(header, &cells);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{cache_path, parse};
+
+    pub(crate) const ENVIRONMENT_INFO: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<Environment xmlns:i="http://www.w3.org/2001/XMLSchema-instance">
+  <AccountInfo><SendPasswordUrl>http://a/pw</SendPasswordUrl><SignInUrl>http://a/in</SignInUrl><SignUpUrl>http://a/up</SignUpUrl></AccountInfo>
+  <GameInfo><AuthenticationUrl>x</AuthenticationUrl><ClientUrl>http://a/reg</ClientUrl><CrashLogUrl>http://a/crash</CrashLogUrl><LauncherUrl>l</LauncherUrl><LauncherUrl2>l2</LauncherUrl2></GameInfo>
+  <PatcherInfo><CiderUrl>c</CiderUrl><ConfigUrl>c</ConfigUrl><InstallUrl>i</InstallUrl></PatcherInfo>
+  <Servers>
+    <Server>
+      <AuthenticationIP>127.0.0.1</AuthenticationIP>
+      <CdnInfo><CpCode>89164</CpCode><PatcherDir>luclient</PatcherDir><PatcherUrl>localhost:8000</PatcherUrl><Secure>false</Secure><UseDlm>false</UseDlm></CdnInfo>
+      <CrispInfo></CrispInfo>
+      <DataCenterId>150</DataCenterId>
+      <GameApiUrl>g</GameApiUrl><GameContentApiUrl>g</GameContentApiUrl>
+      <Language>en_US</Language><LogLevel>1</LogLevel>
+      <MetricsDataServiceUrl>m</MetricsDataServiceUrl>
+      <Name>Overbuild Universe (US)</Name><Online>true</Online><Suggested>true</Suggested>
+      <UGCControllerServicesUrl>u</UGCControllerServicesUrl>
+      <UgcCdnInfo><CpCode>0</CpCode><PatcherDir>ugc</PatcherDir><PatcherUrl>localhost</PatcherUrl><Secure>false</Secure><UseDlm>false</UseDlm></UgcCdnInfo>
+      <Use3DServices>false</Use3DServices><Version>1.10.64</Version><VersionDirType>0</VersionDirType><WebApiUrl>w</WebApiUrl>
+    </Server>
+  </Servers>
+</Environment>"#;
+
+    #[test]
+    fn test_parse() {
+        let env_info = parse(ENVIRONMENT_INFO).unwrap();
+        let server = &env_info.servers.servers[0];
+        assert_eq!(server.name, "Overbuild Universe (US)");
+        assert_eq!(server.cdn_info.patcher_dir, "luclient");
+    }
+
+    #[test]
+    fn test_invalid() {
+        let xml = ENVIRONMENT_INFO.replace("<Language>en_US</Language>", "");
+        let err = parse(&xml).unwrap_err();
+        assert_eq!(err.path, "Environment/Servers/Server[0]");
+        assert_eq!(err.message, "missing field `Language`");
+
+        let xml = ENVIRONMENT_INFO.replace("<Secure>false</Secure>", "<Secure>maybe</Secure>");
+        let err = parse(&xml).unwrap_err();
+        assert_eq!(err.path, "Environment/Servers/Server[0]/CdnInfo/Secure");
+
+        let xml =
+            ENVIRONMENT_INFO.replace("<Name>Overbuild Universe (US)</Name>", "<Name> </Name>");
+        let err = parse(&xml).unwrap_err();
+        assert_eq!(err.path, "Environment/Servers/Server[0]/Name");
+    }
+
+    #[test]
+    fn test_cache_path() {
+        assert!(cache_path("live").is_ok());
+        assert!(cache_path("dev-2").is_ok());
+        for env in ["", "..", "../../foo", "a/b", "a\\b", "C:foo", "a\0b"] {
+            assert!(cache_path(env).is_err(), "{:?}", env);
+        }
+    }
+}
@@ -1,4 +1,11 @@
-use std::path::{Component, Path, PathBuf};
+use assembly_pack::md5::MD5Sum;
+use log::info;
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
 
 pub(crate) fn into_io_error<E>(error: E) -> std::io::Error
 where
@@ -7,11 +14,79 @@ where
     std::io::Error::new(std::io::ErrorKind::Other, error)
 }
 
+/// The directory for this tool within the user directory given by `var`
+///
+/// This follows the XDG base directory spec, falling back to `$HOME/<fallback>`
+/// and `%APPDATA%` on Windows.
+fn user_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    let base = std::env::var_os(var)
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(fallback)))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(base.join("lux-patcher"))
+}
+
+/// The directory for local configuration files
+pub fn config_dir() -> Option<PathBuf> {
+    user_dir("XDG_CONFIG_HOME", ".config")
+}
+
+/// The directory for cached files
+pub fn cache_dir() -> Option<PathBuf> {
+    user_dir("XDG_CACHE_HOME", ".cache")
+}
+
+/// The directory for persistent data, like the installs registry
+pub fn data_dir() -> Option<PathBuf> {
+    user_dir("XDG_DATA_HOME", ".local/share")
+}
+
+/// Append `suffix` to the file name of `path`
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Hardlink `from` to `to`, or copy it if that fails, replacing `to` atomically
+pub fn link_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    let tmp = with_suffix(to, ".link");
+    let _ = std::fs::remove_file(&tmp);
+    if let Err(e) = std::fs::hard_link(from, &tmp) {
+        info!("Copying {}, can't link it: {}", from.display(), e);
+        std::fs::copy(from, &tmp)?;
+    }
+    let res = std::fs::rename(&tmp, to);
+    // The rename does nothing if `to` already is a link to `from`
+    let _ = std::fs::remove_file(&tmp);
+    res
+}
+
+/// Calculate the size and MD5 hash of the file at `path`
+pub fn hash_file(path: &Path) -> std::io::Result<(u64, MD5Sum)> {
+    let mut file = File::open(path)?;
+    let mut context = md5::Context::new();
+    let mut size = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        context.consume(&buf[..len]);
+        size += len as u64;
+    }
+    Ok((size, MD5Sum(context.compute().0)))
+}
+
+/// Resolve `dir` relative to `base`, in place
+///
+/// An absolute `dir`, or one with a drive or share prefix, replaces `base`.
 pub fn join(base: &mut PathBuf, dir: &Path) {
     for c in dir.components() {
         match c {
-            Component::Prefix(_) => todo!(),
-            Component::RootDir => {
+            Component::Prefix(_) | Component::RootDir => {
                 *base = dir.to_owned();
                 break;
             }
@@ -25,3 +100,27 @@ pub fn join(base: &mut PathBuf, dir: &Path) {
         }
     }
 }
+
+/// Print `rows` below `header` as an aligned table
+pub fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let print_row = |row: &[&str]| {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(&header);
+    for row in rows {
+        let row: Vec<&str> = row.iter().map(String::as_str).collect();
+        print_row(&row);
+    }
+}
@@ -0,0 +1,361 @@
+//! Windows-style paths from `patcher.ini`
+//!
+//! Paths like `defaultinstallpath` or `configfile` are written for the Windows
+//! client and use backslashes, drive letters and UNC shares. This module parses
+//! them independently of the host platform, so that the same configuration
+//! resolves identically when a Windows client tree is patched from Linux.
+//! Drives and shares are found on other platforms through a [`DriveMap`].
+
+use std::{fmt, path::PathBuf};
+
+/// The prefix of a Windows path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WinPrefix {
+    /// A drive letter, e.g. `C:`
+    Disk(char),
+    /// A network share, e.g. `\\server\share`
+    Unc(String, String),
+}
+
+impl WinPrefix {
+    /// Whether this is the same drive or share as `other`, ignoring case
+    fn matches(&self, other: &WinPrefix) -> bool {
+        match (self, other) {
+            (Self::Disk(a), Self::Disk(b)) => a.eq_ignore_ascii_case(b),
+            (Self::Unc(server_a, share_a), Self::Unc(server_b, share_b)) => {
+                server_a.eq_ignore_ascii_case(server_b) && share_a.eq_ignore_ascii_case(share_b)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for WinPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disk(letter) => write!(f, "{}:", letter),
+            Self::Unc(server, share) => write!(f, "\\\\{}\\{}", server, share),
+        }
+    }
+}
+
+/// A path that can't be represented on this platform
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedPrefix(pub WinPrefix);
+
+impl fmt::Display for UnsupportedPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Can't resolve a path on '{}' on this platform, map it with --map-drive or specify it explicitly",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedPrefix {}
+
+fn is_separator(c: char) -> bool {
+    c == '\\' || c == '/'
+}
+
+/// A parsed Windows path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WinPath<'a> {
+    /// The drive or share, if any
+    pub prefix: Option<WinPrefix>,
+    /// Whether the path starts at the root (of the prefix)
+    pub has_root: bool,
+    /// The components after the prefix and root, including `.` and `..`
+    pub parts: Vec<&'a str>,
+}
+
+impl<'a> WinPath<'a> {
+    /// Parse a path, accepting both `\` and `/` as separators
+    pub fn parse(input: &'a str) -> Self {
+        // Verbatim (`\\?\`) and device (`\\.\`) paths
+        let verbatim = input
+            .strip_prefix("\\\\?\\")
+            .or_else(|| input.strip_prefix("\\\\.\\"));
+        if let Some(rest) = verbatim {
+            return match rest.strip_prefix("UNC\\") {
+                Some(share) => Self::parse_unc(share),
+                None => Self::parse(rest),
+            };
+        }
+
+        let mut chars = input.chars();
+        let (prefix, rest) = match (chars.next(), chars.next()) {
+            (Some(a), Some(b)) if is_separator(a) && is_separator(b) => {
+                return Self::parse_unc(&input[2..]);
+            }
+            (Some(letter), Some(':')) if letter.is_ascii_alphabetic() => {
+                let prefix = WinPrefix::Disk(letter.to_ascii_uppercase());
+                (Some(prefix), &input[2..])
+            }
+            _ => (None, input),
+        };
+
+        Self {
+            prefix,
+            has_root: rest.starts_with(is_separator),
+            parts: Self::split(rest),
+        }
+    }
+
+    /// Parse `server\share\rest`, after the leading separators
+    fn parse_unc(input: &'a str) -> Self {
+        let mut parts = Self::split(input).into_iter();
+        let server = parts.next().unwrap_or_default();
+        let share = parts.next().unwrap_or_default();
+        Self {
+            prefix: Some(WinPrefix::Unc(server.to_owned(), share.to_owned())),
+            // A share is always absolute
+            has_root: true,
+            parts: parts.collect(),
+        }
+    }
+
+    fn split(rest: &'a str) -> Vec<&'a str> {
+        rest.split(is_separator).filter(|s| !s.is_empty()).collect()
+    }
+
+    /// Convert this path to one for the host platform
+    ///
+    /// Paths below a directory in `drives` are resolved from its native
+    /// directory. Other paths with a prefix are only supported on Windows. On
+    /// other platforms, a rooted path (e.g. `\lu`) is taken to be relative to the
+    /// filesystem root.
+    pub fn to_native(&self, drives: &DriveMap) -> Result<PathBuf, UnsupportedPrefix> {
+        if let Some(path) = drives.resolve(self) {
+            return Ok(path);
+        }
+        let mut path = PathBuf::new();
+        match &self.prefix {
+            // `C:LU` is relative to the current directory on `C:`
+            Some(prefix) if cfg!(windows) && self.has_root => path.push(format!("{}\\", prefix)),
+            Some(prefix) if cfg!(windows) => path.push(prefix.to_string()),
+            Some(prefix) => return Err(UnsupportedPrefix(prefix.clone())),
+            None if self.has_root => path.push(std::path::MAIN_SEPARATOR.to_string()),
+            None => {}
+        }
+        path.extend(&self.parts);
+        Ok(path)
+    }
+}
+
+/// Resolve the `.` and `..` in the parts of an absolute path, as Windows does
+///
+/// A `..` at the root stays there, so the result never leaves the prefix.
+fn normalize<'a>(parts: &[&'a str]) -> Vec<&'a str> {
+    let mut normal = Vec::new();
+    for &part in parts {
+        match part {
+            "." => {}
+            ".." => {
+                normal.pop();
+            }
+            _ => normal.push(part),
+        }
+    }
+    normal
+}
+
+/// The native directories for Windows drives, shares or folders on them
+#[derive(Debug, Clone, Default)]
+pub struct DriveMap {
+    entries: Vec<(WinPrefix, Vec<String>, PathBuf)>,
+}
+
+impl DriveMap {
+    /// Map the absolute Windows directory `from`, e.g. `C:\` or `C:\Games\LU`,
+    /// to the native directory `to`
+    ///
+    /// A single letter is taken to be a drive. Earlier entries win over later
+    /// ones for the same directory.
+    pub fn insert(&mut self, from: &str, to: PathBuf) -> Result<(), String> {
+        let drive;
+        let from = match from.as_bytes() {
+            [letter] if letter.is_ascii_alphabetic() => {
+                drive = format!("{}:\\", from);
+                &drive
+            }
+            _ => from,
+        };
+        let path = WinPath::parse(from);
+        match path.prefix {
+            Some(prefix) if path.has_root || path.parts.is_empty() => {
+                let parts = normalize(&path.parts);
+                let parts = parts.iter().map(|part| part.to_string()).collect();
+                self.entries.push((prefix, parts, to));
+                Ok(())
+            }
+            _ => Err(format!(
+                "expected a drive, share or absolute path with one, got {:?}",
+                from
+            )),
+        }
+    }
+
+    /// Resolve `path` from the longest matching entry
+    ///
+    /// The `..` in `path` are resolved first, so that it can't leave the
+    /// native directory of the entry.
+    fn resolve(&self, path: &WinPath) -> Option<PathBuf> {
+        let prefix = path.prefix.as_ref()?;
+        if !path.has_root {
+            return None;
+        }
+        let path_parts = normalize(&path.parts);
+        let mut best: Option<&(WinPrefix, Vec<String>, PathBuf)> = None;
+        for entry in &self.entries {
+            let (from, parts, _) = entry;
+            let matches = from.matches(prefix)
+                && parts.len() <= path_parts.len()
+                && parts
+                    .iter()
+                    .zip(&path_parts)
+                    .all(|(a, b)| a.eq_ignore_ascii_case(b));
+            let longer = best.map_or(true, |(_, best, _)| parts.len() > best.len());
+            if matches && longer {
+                best = Some(entry);
+            }
+        }
+        let (_, parts, to) = best?;
+        let mut native = to.clone();
+        native.extend(&path_parts[parts.len()..]);
+        Some(native)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DriveMap, WinPath, WinPrefix};
+    use crate::util::join;
+    use std::path::{Path, PathBuf};
+
+    fn disk(c: char) -> Option<WinPrefix> {
+        Some(WinPrefix::Disk(c))
+    }
+
+    fn unc(server: &str, share: &str) -> Option<WinPrefix> {
+        Some(WinPrefix::Unc(server.to_owned(), share.to_owned()))
+    }
+
+    #[test]
+    fn test_parse() {
+        let cases: &[(&str, Option<WinPrefix>, bool, &[&str])] = &[
+            ("..", None, false, &[".."]),
+            ("client\\boot.cfg", None, false, &["client", "boot.cfg"]),
+            (
+                "client/res\\macros",
+                None,
+                false,
+                &["client", "res", "macros"],
+            ),
+            ("\\lu\\client", None, true, &["lu", "client"]),
+            ("/home/lu/", None, true, &["home", "lu"]),
+            ("C:\\Games\\LU", disk('C'), true, &["Games", "LU"]),
+            ("d:/LU", disk('D'), true, &["LU"]),
+            ("C:LU", disk('C'), false, &["LU"]),
+            ("C:", disk('C'), false, &[]),
+            ("\\\\nas\\games\\LU", unc("nas", "games"), true, &["LU"]),
+            ("//nas/games", unc("nas", "games"), true, &[]),
+            ("\\\\?\\C:\\LU", disk('C'), true, &["LU"]),
+            (
+                "\\\\?\\UNC\\nas\\games\\LU",
+                unc("nas", "games"),
+                true,
+                &["LU"],
+            ),
+            ("..\\..\\LU", None, false, &["..", "..", "LU"]),
+        ];
+        for (input, prefix, has_root, parts) in cases {
+            let path = WinPath::parse(input);
+            assert_eq!(&path.prefix, prefix, "prefix of {:?}", input);
+            assert_eq!(path.has_root, *has_root, "root of {:?}", input);
+            assert_eq!(&path.parts, parts, "parts of {:?}", input);
+        }
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_resolve() {
+        let base = Path::new("/opt/lu/patcher");
+        let cases: &[(&str, Option<&str>)] = &[
+            ("..", Some("/opt/lu")),
+            (".\\client", Some("/opt/lu/patcher/client")),
+            ("..\\client\\boot.cfg", Some("/opt/lu/client/boot.cfg")),
+            ("/srv/lu\\client\\boot.cfg", Some("/srv/lu/client/boot.cfg")),
+            ("\\srv\\lu", Some("/srv/lu")),
+            ("C:\\Games\\LU", None),
+            ("\\\\nas\\games\\LU", None),
+        ];
+        for (input, expected) in cases {
+            let res = WinPath::parse(input)
+                .to_native(&DriveMap::default())
+                .ok()
+                .map(|native| {
+                    let mut dir = base.to_owned();
+                    join(&mut dir, &native);
+                    dir
+                });
+            assert_eq!(res, expected.map(PathBuf::from), "resolving {:?}", input);
+        }
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_resolve() {
+        let base = Path::new("C:\\LU\\patcher");
+        let cases: &[(&str, &str)] = &[
+            ("..", "C:\\LU"),
+            ("..\\client\\boot.cfg", "C:\\LU\\client\\boot.cfg"),
+            ("D:\\Games\\LU", "D:\\Games\\LU"),
+            ("D:LU", "D:LU"),
+            ("\\\\nas\\games\\LU", "\\\\nas\\games\\LU"),
+        ];
+        for (input, expected) in cases {
+            let native = WinPath::parse(input)
+                .to_native(&DriveMap::default())
+                .unwrap();
+            let mut dir = base.to_owned();
+            join(&mut dir, &native);
+            assert_eq!(dir, PathBuf::from(expected), "resolving {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_drive_map() {
+        let mut drives = DriveMap::default();
+        drives.insert("C", PathBuf::from("/mnt/c")).unwrap();
+        drives
+            .insert("C:\\Games\\LU", PathBuf::from("/srv/lu"))
+            .unwrap();
+        drives
+            .insert("\\\\nas\\games", PathBuf::from("/mnt/games"))
+            .unwrap();
+        assert!(drives.insert("LU", PathBuf::from("/srv/lu")).is_err());
+        assert!(drives.insert("C:LU", PathBuf::from("/srv/lu")).is_err());
+
+        let cases: &[(&str, Option<&str>)] = &[
+            ("C:\\Windows", Some("/mnt/c/Windows")),
+            (
+                "c:\\games\\lu\\client\\boot.cfg",
+                Some("/srv/lu/client/boot.cfg"),
+            ),
+            ("C:\\Games\\LUX", Some("/mnt/c/Games/LUX")),
+            ("\\\\NAS\\Games\\LU", Some("/mnt/games/LU")),
+            ("C:\\Games\\LU\\..\\..\\etc", Some("/mnt/c/etc")),
+            ("C:\\Games\\LU\\.\\client", Some("/srv/lu/client")),
+            ("\\\\nas\\games\\..\\..\\etc", Some("/mnt/games/etc")),
+            ("D:\\LU", None),
+            ("C:LU", None),
+            ("\\\\nas\\other\\LU", None),
+        ];
+        for (input, expected) in cases {
+            let res = drives.resolve(&WinPath::parse(input));
+            assert_eq!(res, expected.map(PathBuf::from), "resolving {:?}", input);
+        }
+    }
+}